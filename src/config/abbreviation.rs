@@ -0,0 +1,95 @@
+use serde::Deserialize;
+use serde_with::{serde_as, OneOrMany};
+use smart_default::SmartDefault;
+
+use crate::*;
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum AbbreviationMode {
+    #[serde(alias = "i", alias = "insert")]
+    Insert,
+    #[serde(alias = "c", alias = "command")]
+    Command,
+}
+
+#[serde_as]
+#[derive(Debug, Deserialize, SmartDefault, Clone)]
+#[serde(default)]
+pub struct Abbreviations {
+    /// Modes the abbreviations are defined in, all if empty.
+    #[serde(alias = "mode")]
+    #[serde_as(deserialize_as = "OneOrMany<_>")]
+    modes: Vec<AbbreviationMode>,
+    #[default = true]
+    recursive: bool,
+    silent: bool,
+    expression: bool,
+    /// Overrides whether the abbreviations are buffer-local.
+    buffer: Option<bool>,
+    #[serde(flatten)]
+    abbreviations: HashMap<String, String>,
+}
+
+/// Escapes `text` for use as lhs or rhs of an `:abbreviate` command.
+fn escape(text: &str) -> String {
+    text.replace('\\', "<Bslash>")
+        .replace('|', "<Bar>")
+        .replace('\n', "<CR>")
+}
+
+impl Abbreviations {
+    pub fn count(&self) -> usize {
+        self.modes.len().max(1) * self.abbreviations.len()
+    }
+
+    pub fn default_buffer(&mut self, buffer: bool) {
+        self.buffer.get_or_insert(buffer);
+    }
+
+    /// Abbreviations that cannot be defined.
+    pub fn invalid(&self) -> impl Iterator<Item = &str> {
+        self.abbreviations
+            .keys()
+            .map(String::as_str)
+            .filter(|lhs| lhs.is_empty() || lhs.contains(char::is_whitespace))
+    }
+
+    pub fn apply(&self, buffer: bool) -> ApiResult {
+        let mut arguments = String::new();
+        if self.buffer.unwrap_or(buffer) {
+            arguments.push_str(" <buffer>");
+        }
+        if self.silent {
+            arguments.push_str(" <silent>");
+        }
+        if self.expression {
+            arguments.push_str(" <expr>");
+        }
+        let noremap = if self.recursive { "" } else { "nore" };
+        let prefixes = if self.modes.is_empty() {
+            vec![""]
+        } else {
+            self.modes
+                .iter()
+                .map(|mode| match mode {
+                    AbbreviationMode::Insert => "i",
+                    AbbreviationMode::Command => "c",
+                })
+                .collect()
+        };
+        for (lhs, rhs) in &self.abbreviations {
+            if lhs.is_empty() || lhs.contains(char::is_whitespace) {
+                log_error!("Abbreviation `{lhs}` must be a single word");
+                continue;
+            }
+            for prefix in &prefixes {
+                api::command(&format!(
+                    "{prefix}{noremap}abbrev{arguments} {} {}",
+                    escape(lhs),
+                    escape(rhs)
+                ))?;
+            }
+        }
+        Ok(())
+    }
+}
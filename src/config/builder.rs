@@ -0,0 +1,85 @@
+use std::mem;
+
+use oxi::api::types::Mode;
+
+use crate::*;
+
+/// Builds a [`Config`] in code, equivalent to the same entries in a config file.
+///
+/// Applying needs to run inside Neovim, e.g. in an `#[nvim_oxi::test]`:
+/// ```no_run
+/// use config::{api::types::Mode, config::Config};
+///
+/// let config = Config::builder()
+///     .set("number", true)
+///     .keymap(Mode::Normal, "<Leader>w", "<Cmd>write<CR>")
+///     .autocmd("BufWritePre", "lua vim.lsp.buf.format()")
+///     .build();
+/// config.apply(false)?;
+/// # Ok::<_, config::ApiError>(())
+/// ```
+#[derive(Default)]
+pub struct ConfigBuilder {
+    config: Config,
+}
+
+impl Config {
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder::default()
+    }
+}
+
+impl ConfigBuilder {
+    /// Assigns `value` to option `name`.
+    pub fn set(&mut self, name: impl Into<String>, value: impl Into<SetValue>) -> &mut Self {
+        self.set_with(name, Operation::Assign, value)
+    }
+
+    /// Modifies option `name` with `value` through `operation`.
+    pub fn set_with(
+        &mut self,
+        name: impl Into<String>,
+        operation: Operation,
+        value: impl Into<SetValue>,
+    ) -> &mut Self {
+        self.config.set.push(Set(
+            name.into(),
+            operation,
+            value.into(),
+            SetModifiers::default(),
+        ));
+        self
+    }
+
+    /// Maps `lhs` to `rhs` in `mode` non-recursively.
+    pub fn keymap(
+        &mut self,
+        mode: Mode,
+        lhs: impl Into<String>,
+        rhs: impl Into<String>,
+    ) -> &mut Self {
+        self.keys(Keys::mapping(mode, lhs, rhs))
+    }
+
+    pub fn keys(&mut self, keys: Keys) -> &mut Self {
+        self.config.keys.push(keys);
+        self
+    }
+
+    /// Runs the ex command `cmd` on `event`.
+    pub fn autocmd(&mut self, event: impl Into<String>, cmd: impl Into<String>) -> &mut Self {
+        self.config
+            .auto_commands
+            .push(AutoCommand::command(vec![event.into()], cmd));
+        self
+    }
+
+    pub fn condition(&mut self, condition: Condition) -> &mut Self {
+        self.config.conditions.push(condition);
+        self
+    }
+
+    pub fn build(&mut self) -> Config {
+        mem::take(&mut self.config)
+    }
+}
@@ -0,0 +1,235 @@
+use oxi::api::{
+    types::{CommandComplete, CommandNArgs, CommandRange},
+    Buffer,
+};
+use serde::Deserialize;
+
+use crate::*;
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum UserCommandDeserializer {
+    /// Ex command run by the user command.
+    Command(String),
+    Full(UserCommand),
+}
+
+impl From<UserCommandDeserializer> for UserCommand {
+    fn from(d: UserCommandDeserializer) -> Self {
+        match d {
+            UserCommandDeserializer::Command(command) => Self {
+                command: Some(command),
+                ..Self::default()
+            },
+            UserCommandDeserializer::Full(command) => command,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Default, Clone)]
+#[serde(default)]
+pub struct UserCommand {
+    command: Option<String>,
+    /// Lua function called instead of `command`.
+    lua: Option<String>,
+    nargs: Option<NArgs>,
+    bang: bool,
+    range: Option<Range>,
+    desc: Option<String>,
+    complete: Option<Complete>,
+    /// Overrides whether the command is buffer-local.
+    buffer: Option<bool>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum NArgs {
+    Count(u8),
+    /// One of `?`, `*` and `+`.
+    Spec(String),
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum Range {
+    /// `true` for a range defaulting to the current line.
+    CurrentLine(bool),
+    /// Count defaulting to this number.
+    Count(u32),
+    /// `%` for a range defaulting to the whole file.
+    WholeFile(String),
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum Complete {
+    /// Fixed list of candidates.
+    Values(Vec<String>),
+    Builtin(BuiltinComplete),
+}
+
+#[derive(Debug, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum BuiltinComplete {
+    Arglist,
+    Augroup,
+    Buffer,
+    Color,
+    Command,
+    Dir,
+    Environment,
+    Event,
+    File,
+    Filetype,
+    Help,
+    Highlight,
+    Lua,
+    Mapping,
+    Option,
+    Shellcmd,
+    User,
+    Var,
+}
+
+impl From<BuiltinComplete> for CommandComplete {
+    fn from(complete: BuiltinComplete) -> Self {
+        match complete {
+            BuiltinComplete::Arglist => Self::Arglist,
+            BuiltinComplete::Augroup => Self::Augroup,
+            BuiltinComplete::Buffer => Self::Buffer,
+            BuiltinComplete::Color => Self::Color,
+            BuiltinComplete::Command => Self::Command,
+            BuiltinComplete::Dir => Self::Dir,
+            BuiltinComplete::Environment => Self::Environment,
+            BuiltinComplete::Event => Self::Event,
+            BuiltinComplete::File => Self::File,
+            BuiltinComplete::Filetype => Self::Filetype,
+            BuiltinComplete::Help => Self::Help,
+            BuiltinComplete::Highlight => Self::Highlight,
+            BuiltinComplete::Lua => Self::Lua,
+            BuiltinComplete::Mapping => Self::Mapping,
+            BuiltinComplete::Option => Self::Option,
+            BuiltinComplete::Shellcmd => Self::Shellcmd,
+            BuiltinComplete::User => Self::User,
+            BuiltinComplete::Var => Self::Var,
+        }
+    }
+}
+
+impl NArgs {
+    fn to_command_nargs(&self) -> Result<CommandNArgs, String> {
+        Ok(match self {
+            NArgs::Count(0) => CommandNArgs::Zero,
+            NArgs::Count(1) => CommandNArgs::One,
+            NArgs::Spec(spec) => match spec.as_str() {
+                "0" => CommandNArgs::Zero,
+                "1" => CommandNArgs::One,
+                "?" => CommandNArgs::ZeroOrOne,
+                "*" => CommandNArgs::Any,
+                "+" => CommandNArgs::OneOrMore,
+                spec => return Err(format!("invalid nargs `{spec}`")),
+            },
+            NArgs::Count(count) => return Err(format!("invalid nargs `{count}`")),
+        })
+    }
+}
+
+impl Range {
+    fn to_command_range(&self) -> Result<Option<CommandRange>, String> {
+        Ok(match self {
+            Range::CurrentLine(true) => Some(CommandRange::CurrentLine),
+            Range::CurrentLine(false) => None,
+            Range::Count(count) => Some(CommandRange::Count(*count)),
+            Range::WholeFile(range) if range == "%" => Some(CommandRange::WholeFile),
+            Range::WholeFile(range) => return Err(format!("invalid range `{range}`")),
+        })
+    }
+}
+
+impl UserCommand {
+    pub fn runs_lua(&self) -> bool {
+        self.lua.is_some()
+    }
+
+    pub fn default_buffer(&mut self, buffer: bool) {
+        self.buffer.get_or_insert(buffer);
+    }
+
+    pub fn validate_name(name: &str) -> Result<(), String> {
+        if name.starts_with(|c: char| c.is_ascii_uppercase()) {
+            Ok(())
+        } else {
+            Err(format!(
+                "User command `{name}` must start with an uppercase letter"
+            ))
+        }
+    }
+
+    pub fn apply(&self, name: &str, buffer: bool) -> ApiResult {
+        if let Err(error) = Self::validate_name(name) {
+            log_error!("{error}");
+            return Ok(());
+        }
+        let command = match (&self.command, &self.lua) {
+            (_, Some(lua)) => format!("lua {lua}{}", if lua.ends_with(')') { "" } else { "()" }),
+            (Some(command), None) => command.clone(),
+            (None, None) => {
+                log_error!("User command `{name}` needs a `command` or `lua`");
+                return Ok(());
+            }
+        };
+
+        let mut opts = CreateCommandOpts::builder();
+        opts.bang(self.bang);
+        if let Some(nargs) = &self.nargs {
+            match nargs.to_command_nargs() {
+                Ok(nargs) => {
+                    opts.nargs(nargs);
+                }
+                Err(error) => {
+                    log_error!("User command `{name}` has {error}");
+                    return Ok(());
+                }
+            }
+        }
+        if let Some(range) = &self.range {
+            match range.to_command_range() {
+                Ok(Some(range)) => {
+                    opts.range(range);
+                }
+                Ok(None) => {}
+                Err(error) => {
+                    log_error!("User command `{name}` has {error}");
+                    return Ok(());
+                }
+            }
+        }
+        if let Some(desc) = &self.desc {
+            opts.desc(desc.as_str());
+        }
+        match &self.complete {
+            Some(Complete::Values(values)) => {
+                let values = values.clone();
+                opts.complete(CommandComplete::CustomList(Function::from_fn(
+                    move |(arg_lead, _, _): (String, String, usize)| -> Result<Vec<String>> {
+                        Ok(values
+                            .iter()
+                            .filter(|value| value.starts_with(&arg_lead))
+                            .cloned()
+                            .collect())
+                    },
+                )));
+            }
+            Some(Complete::Builtin(complete)) => {
+                opts.complete((*complete).into());
+            }
+            None => {}
+        }
+
+        if self.buffer.unwrap_or(buffer) {
+            Buffer::current().create_user_command(name, command.as_str(), &opts.build())
+        } else {
+            api::create_user_command(name, command.as_str(), &opts.build())
+        }
+    }
+}
@@ -1,4 +1,10 @@
-use oxi::api::opts::{CreateAutocmdOpts, CreateAutocmdOptsBuilder};
+use std::{
+    fmt::{self, Display},
+    mem,
+};
+
+use oxi::{api::Buffer, Array, Object};
+use semver::{Version, VersionReq};
 use serde::Deserialize;
 use serde_with::{serde_as, OneOrMany};
 
@@ -11,21 +17,405 @@ pub struct Condition {
     #[serde(default)]
     #[serde_as(deserialize_as = "OneOrMany<_>")]
     filetype: Vec<String>,
+    /// Filetypes of buffers the condition never matches.
+    #[serde(alias = "except_filetype")]
+    #[serde_as(deserialize_as = "OneOrMany<_>")]
+    not_filetype: Vec<String>,
+    #[serde(default)]
+    #[serde_as(deserialize_as = "OneOrMany<_>")]
+    background: Vec<String>,
+    readonly: Option<bool>,
+    modifiable: Option<bool>,
+    /// Semver requirement on the running Neovim, checked once when loading.
+    nvim_version: Option<String>,
+    /// Features that `has()` needs to report, e.g. `clipboard` or `wsl`, checked once when loading.
+    #[serde(alias = "feature")]
+    #[serde_as(deserialize_as = "OneOrMany<_>")]
+    has: Vec<String>,
+    /// Lua expressions that all need to be truthy, evaluated once when loading.
+    #[serde_as(deserialize_as = "OneOrMany<_>")]
+    when: Vec<String>,
+    /// Lua modules that need to be available for `require`, checked once when loading.
+    #[serde_as(deserialize_as = "OneOrMany<_>")]
+    has_module: Vec<String>,
+    /// Events the config is applied on instead of immediately, e.g. `UIEnter`.
+    #[serde(alias = "defer")]
+    #[serde_as(deserialize_as = "OneOrMany<_>")]
+    on: Vec<String>,
+    /// Whether buffer-local configs are applied after the `FileType` autocommands of ftplugins,
+    /// so those cannot override the options set.
+    after_ftplugin: bool,
+    /// Conditions that all need to match as well.
+    #[serde_as(deserialize_as = "OneOrMany<_>")]
+    all: Vec<Condition>,
+    /// Conditions of which at least one needs to match as well.
+    #[serde_as(deserialize_as = "OneOrMany<_>")]
+    any: Vec<Condition>,
+}
+
+#[derive(Deserialize)]
+struct ApiInfo {
+    version: ApiVersion,
+}
+
+#[derive(Deserialize)]
+struct ApiVersion {
+    major: u64,
+    minor: u64,
+    patch: u64,
+}
+
+fn nvim_version() -> Result<Version, String> {
+    let info: Object = api::call_function("api_info", Array::new())
+        .map_err(|error| format!("unable to get Neovim version: {error}"))?;
+    let ApiInfo {
+        version: ApiVersion {
+            major,
+            minor,
+            patch,
+        },
+    } = ApiInfo::deserialize(oxi::serde::Deserializer::new(info))
+        .map_err(|error| format!("unable to get Neovim version: {error}"))?;
+    Ok(Version::new(major, minor, patch))
+}
+
+fn intersects(a: &[String], b: &[String]) -> bool {
+    a.is_empty() || b.is_empty() || a.iter().any(|a| b.contains(a))
+}
+
+/// Whether every one of `filetypes` is excluded by `not_filetype`.
+fn excluded(filetypes: &[String], not_filetype: &[String]) -> bool {
+    !filetypes.is_empty()
+        && filetypes
+            .iter()
+            .all(|filetype| not_filetype.contains(filetype))
+}
+
+/// Options of the current buffer or window instead of the global ones.
+fn local_option() -> OptionValueOpts {
+    OptionValueOpts::builder().scope(OptionScope::Local).build()
+}
+
+fn compatible(a: Option<bool>, b: Option<bool>) -> bool {
+    a.is_none() || b.is_none() || a == b
+}
+
+/// Whether `a` and `b` contain the same values, in any order.
+fn same(a: &[String], b: &[String]) -> bool {
+    a.len() == b.len() && a.iter().all(|a| b.contains(a))
+}
+
+/// Whether checks made once when loading can both hold, different ones are assumed not to.
+fn guards_compatible<T: PartialEq>(a: &[T], b: &[T]) -> bool {
+    a.is_empty() || b.is_empty() || a == b
+}
+
+/// Values allowed by both `a` and `b`, `None` if there are none.
+fn restrict(a: &[String], b: &[String]) -> Option<Vec<String>> {
+    if a.is_empty() || b.is_empty() {
+        return Some([a, b].concat());
+    }
+    let both: Vec<_> = a.iter().filter(|a| b.contains(a)).cloned().collect();
+    (!both.is_empty()).then_some(both)
 }
 
 impl Condition {
-    pub fn events(&self) -> Vec<String> {
+    /// Resolves `all` and `any` into alternatives without them, dropping combinations that can
+    /// never match.
+    pub fn flatten(mut self) -> Vec<Self> {
+        let all = mem::take(&mut self.all);
+        let any = mem::take(&mut self.any);
+        let mut alternatives = vec![self];
+        for condition in all {
+            alternatives = alternatives
+                .iter()
+                .cartesian_product(&condition.flatten())
+                .filter_map(|(a, b)| a.and(b))
+                .collect();
+        }
+        if !any.is_empty() {
+            let any: Vec<_> = any.into_iter().flat_map(Self::flatten).collect();
+            alternatives = alternatives
+                .iter()
+                .cartesian_product(&any)
+                .filter_map(|(a, b)| a.and(b))
+                .collect();
+        }
+        alternatives
+    }
+
+    /// Condition matching when both `self` and `other` match, expects both to be flat.
+    fn and(&self, other: &Self) -> Option<Self> {
+        if !compatible(self.readonly, other.readonly)
+            || !compatible(self.modifiable, other.modifiable)
+        {
+            return None;
+        }
+        let not_filetype = [&self.not_filetype[..], &other.not_filetype]
+            .concat()
+            .into_iter()
+            .unique()
+            .collect_vec();
+        let filetype = restrict(&self.filetype, &other.filetype)?;
+        if excluded(&filetype, &not_filetype) {
+            return None;
+        }
+        Some(Self {
+            filetype,
+            not_filetype,
+            background: restrict(&self.background, &other.background)?,
+            readonly: self.readonly.or(other.readonly),
+            modifiable: self.modifiable.or(other.modifiable),
+            nvim_version: match (&self.nvim_version, &other.nvim_version) {
+                (Some(a), Some(b)) => Some(format!("{a}, {b}")),
+                (a, b) => a.clone().or_else(|| b.clone()),
+            },
+            has: [&self.has[..], &other.has]
+                .concat()
+                .into_iter()
+                .unique()
+                .collect(),
+            when: [&self.when[..], &other.when]
+                .concat()
+                .into_iter()
+                .unique()
+                .collect(),
+            has_module: [&self.has_module[..], &other.has_module]
+                .concat()
+                .into_iter()
+                .unique()
+                .collect(),
+            on: [&self.on[..], &other.on]
+                .concat()
+                .into_iter()
+                .unique()
+                .collect(),
+            after_ftplugin: self.after_ftplugin || other.after_ftplugin,
+            ..Self::default()
+        })
+    }
+
+    /// Condition of a filetype section, matching buffers of `filetype` when one of `conditions`
+    /// does, if any.
+    pub fn section(filetype: String, conditions: Vec<Condition>) -> Self {
+        Self {
+            filetype: vec![filetype],
+            any: conditions,
+            ..Self::default()
+        }
+    }
+
+    /// Checks `nvim_version` against the running Neovim, returning the condition without it
+    /// when satisfied.
+    pub fn resolve_version(mut self) -> Option<Self> {
+        let Some(requirement) = self.nvim_version.take() else {
+            return Some(self);
+        };
+        let matches = VersionReq::parse(&requirement)
+            .map_err(|error| format!("invalid nvim_version `{requirement}`: {error}"))
+            .and_then(|requirement| Ok(requirement.matches(&nvim_version()?)));
+        match matches {
+            Ok(true) => Some(self),
+            Ok(false) => None,
+            Err(error) => {
+                log_error!("{error}");
+                None
+            }
+        }
+    }
+
+    /// Checks that `has()` reports every feature of `has`, returning the condition without them
+    /// when it does.
+    pub fn resolve_features(self) -> Option<Self> {
+        self.resolve_features_with(|feature| api::call_function("has", (feature,)))
+    }
+
+    /// Like [`Condition::resolve_features`], asking `has` whether a feature is available.
+    fn resolve_features_with(mut self, has: impl Fn(&str) -> ApiResult<i64>) -> Option<Self> {
+        for feature in mem::take(&mut self.has) {
+            match has(&feature) {
+                Ok(1) => {}
+                Ok(_) => return None,
+                Err(error) => {
+                    log_error!("unable to check for feature `{feature}`: {error}");
+                    return None;
+                }
+            }
+        }
+        Some(self)
+    }
+
+    /// Evaluates the expressions of `when`, returning the condition without them when all are
+    /// truthy, failing ones are reported and count as falsy.
+    pub fn resolve_when(mut self) -> Option<Self> {
+        for expression in mem::take(&mut self.when) {
+            let truthy: ApiResult<bool> =
+                api::call_function("luaeval", (format!("not not ({expression})"),));
+            match truthy {
+                Ok(true) => {}
+                Ok(false) => return None,
+                Err(error) => {
+                    log_error!("unable to evaluate `{expression}`: {error}");
+                    return None;
+                }
+            }
+        }
+        Some(self)
+    }
+
+    /// Checks that every module of `has_module` can be required, returning the condition without
+    /// them when they can.
+    pub fn resolve_modules(mut self) -> Option<Self> {
+        for module in mem::take(&mut self.has_module) {
+            let available: ApiResult<bool> =
+                api::call_function("luaeval", ("pcall(require, _A)", module.as_str()));
+            match available {
+                Ok(true) => {}
+                Ok(false) => return None,
+                Err(error) => {
+                    log_error!("unable to check for module `{module}`: {error}");
+                    return None;
+                }
+            }
+        }
+        Some(self)
+    }
+
+    /// Events and their patterns after which the condition could have started matching.
+    pub fn autocmds(&self) -> Vec<(&str, Vec<String>)> {
         let mut ret = Vec::new();
+        for event in &self.on {
+            ret.push((event.as_str(), vec!["*".to_string()]));
+        }
         if !self.filetype.is_empty() {
-            ret.push("FileType".to_string());
+            ret.push(("FileType", self.filetype.clone()));
+        } else if !self.not_filetype.is_empty() {
+            ret.push(("FileType", vec!["*".to_string()]));
+        }
+        if !self.background.is_empty() {
+            ret.push(("OptionSet", vec!["background".to_string()]));
+        }
+        let buffer_options: Vec<_> = [("readonly", self.readonly), ("modifiable", self.modifiable)]
+            .into_iter()
+            .filter(|(_, value)| value.is_some())
+            .map(|(option, _)| option.to_string())
+            .collect();
+        if !buffer_options.is_empty() {
+            ret.push(("BufReadPost", vec!["*".to_string()]));
+            ret.push(("OptionSet", buffer_options));
         }
         ret
     }
-    pub fn opts(&self) -> CreateAutocmdOptsBuilder {
-        CreateAutocmdOpts::builder()
-            // .group(StrI64::String(String::from("Config")))
-            .patterns(self.filetype.iter().map(AsRef::as_ref))
-            .clone()
+
+    /// Whether configs under this condition wait for one of the `on` events.
+    pub fn is_deferred(&self) -> bool {
+        !self.on.is_empty()
+    }
+
+    pub fn is_after_ftplugin(&self) -> bool {
+        self.after_ftplugin
+    }
+
+    /// Whether configs under this condition are applied to single buffers.
+    pub fn is_buffer_local(&self) -> bool {
+        !self.filetype.is_empty()
+            || !self.not_filetype.is_empty()
+            || self.readonly.is_some()
+            || self.modifiable.is_some()
+    }
+
+    pub fn matches(&self, buffer: &Buffer) -> ApiResult<bool> {
+        if !self.filetype.is_empty() || !self.not_filetype.is_empty() {
+            let filetype: String =
+                buffer.call(|()| api::get_option_value("filetype", &local_option()))?;
+            if (!self.filetype.is_empty() && !self.filetype.contains(&filetype))
+                || self.not_filetype.contains(&filetype)
+            {
+                return Ok(false);
+            }
+        }
+        if !self.background.is_empty() {
+            let background: String =
+                api::get_option_value("background", &OptionValueOpts::builder().build())?;
+            if !self.background.contains(&background) {
+                return Ok(false);
+            }
+        }
+        for (option, expected) in [("readonly", self.readonly), ("modifiable", self.modifiable)] {
+            if let Some(expected) = expected {
+                let value: bool =
+                    buffer.call(move |()| api::get_option_value(option, &local_option()))?;
+                if value != expected {
+                    return Ok(false);
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// Whether a single buffer can be matched by both `self` and `other` at the same time.
+    ///
+    /// Configs applied on different events are not, the one applied later wins.
+    pub fn overlaps(&self, other: &Self) -> bool {
+        *self != Self::default()
+            && *other != Self::default()
+            && intersects(&self.filetype, &other.filetype)
+            && !excluded(&self.filetype, &other.not_filetype)
+            && !excluded(&other.filetype, &self.not_filetype)
+            && intersects(&self.background, &other.background)
+            && compatible(self.readonly, other.readonly)
+            && compatible(self.modifiable, other.modifiable)
+            && same(&self.on, &other.on)
+            && guards_compatible(self.nvim_version.as_slice(), other.nvim_version.as_slice())
+            && guards_compatible(&self.has, &other.has)
+            && guards_compatible(&self.when, &other.when)
+            && guards_compatible(&self.has_module, &other.has_module)
+    }
+}
+
+impl Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = Vec::new();
+        if !self.filetype.is_empty() {
+            parts.push(format!("filetype = {}", self.filetype.join(", ")));
+        }
+        if !self.not_filetype.is_empty() {
+            parts.push(format!("not filetype = {}", self.not_filetype.join(", ")));
+        }
+        if !self.background.is_empty() {
+            parts.push(format!("background = {}", self.background.join(", ")));
+        }
+        if let Some(readonly) = self.readonly {
+            parts.push(format!("readonly = {readonly}"));
+        }
+        if let Some(modifiable) = self.modifiable {
+            parts.push(format!("modifiable = {modifiable}"));
+        }
+        if let Some(nvim_version) = &self.nvim_version {
+            parts.push(format!("nvim_version = {nvim_version}"));
+        }
+        if !self.has.is_empty() {
+            parts.push(format!("has = {}", self.has.join(", ")));
+        }
+        if !self.when.is_empty() {
+            parts.push(format!("when = {}", self.when.join(", ")));
+        }
+        if !self.has_module.is_empty() {
+            parts.push(format!("has_module = {}", self.has_module.join(", ")));
+        }
+        if !self.on.is_empty() {
+            parts.push(format!("on = {}", self.on.join(", ")));
+        }
+        if self.after_ftplugin {
+            parts.push("after ftplugin".to_owned());
+        }
+        if !self.all.is_empty() {
+            parts.push(format!("all = ({})", self.all.iter().join("; ")));
+        }
+        if !self.any.is_empty() {
+            parts.push(format!("any = ({})", self.any.iter().join("; ")));
+        }
+        write!(f, "{}", parts.join(" and "))
     }
 }
 
@@ -35,12 +425,155 @@ impl IntoIterator for Condition {
     type IntoIter = <Vec<Condition> as IntoIterator>::IntoIter;
 
     fn into_iter(self) -> Self::IntoIter {
+        if self.filetype.is_empty() {
+            return if self == Self::default() {
+                Vec::new()
+            } else {
+                vec![self]
+            }
+            .into_iter();
+        }
         self.filetype
-            .into_iter()
+            .iter()
             .map(|filetype| Condition {
-                filetype: vec![filetype],
+                filetype: vec![filetype.clone()],
+                ..self.clone()
             })
             .collect::<Vec<_>>()
             .into_iter()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flatten(source: &str) -> Vec<String> {
+        toml::from_str::<Condition>(source)
+            .expect("condition is valid")
+            .flatten()
+            .iter()
+            .map(ToString::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn flatten_all_and_any() {
+        assert_eq!(
+            flatten(
+                r#"all = [{ filetype = ["rust", "lua"] }, { filetype = "rust", readonly = false }]"#
+            ),
+            ["filetype = rust and readonly = false"]
+        );
+        assert_eq!(
+            flatten(
+                r#"readonly = true
+                any = [{ filetype = "rust" }, { filetype = "lua" }]"#
+            ),
+            [
+                "filetype = rust and readonly = true",
+                "filetype = lua and readonly = true"
+            ]
+        );
+        assert_eq!(
+            flatten(
+                r#"filetype = "rust"
+                any = [{ not_filetype = "rust" }, { background = "dark" }]"#
+            ),
+            ["filetype = rust and background = dark"]
+        );
+        assert!(flatten("readonly = true\nall = { readonly = false }").is_empty());
+    }
+
+    #[test]
+    fn features_are_resolved_by_query() {
+        let condition =
+            toml::from_str::<Condition>("filetype = \"rust\"\nhas = [\"wsl\", \"clipboard\"]")
+                .expect("condition is valid");
+        let has = |available: &'static [&'static str]| {
+            move |feature: &str| -> ApiResult<i64> {
+                Ok(i64::from(
+                    available.iter().any(|&available| available == feature),
+                ))
+            }
+        };
+        let resolved = condition
+            .clone()
+            .resolve_features_with(has(&["clipboard", "wsl"]))
+            .expect("all features are available");
+        assert_eq!(resolved.to_string(), "filetype = rust");
+        assert!(condition
+            .resolve_features_with(has(&["clipboard"]))
+            .is_none());
+    }
+
+    #[nvim_oxi::test]
+    fn modules_need_to_be_available() {
+        let condition = |module: &str| {
+            toml::from_str::<Condition>(&format!("filetype = \"lua\"\nhas_module = \"{module}\""))
+                .expect("condition is valid")
+                .resolve_modules()
+        };
+        assert_eq!(
+            condition("vim.lsp").map(|condition| condition.to_string()),
+            Some("filetype = lua".to_owned())
+        );
+        assert!(condition("config_nvim_absent").is_none());
+    }
+
+    #[nvim_oxi::test]
+    fn when_needs_truthy_expressions() {
+        let condition = |expression: &str| {
+            toml::from_str::<Condition>(&format!("filetype = \"lua\"\nwhen = {expression:?}"))
+                .expect("condition is valid")
+                .resolve_when()
+        };
+        assert_eq!(
+            condition("1 + 1 == 2").map(|condition| condition.to_string()),
+            Some("filetype = lua".to_owned())
+        );
+        assert!(condition("vim.g.config_nvim_unset").is_none());
+        assert!(logged_messages().is_empty());
+        assert!(condition("1 +").is_none());
+        assert_eq!(logged_messages().len(), 1);
+    }
+
+    #[nvim_oxi::test]
+    fn matches_read_the_options_of_the_buffer() {
+        let condition = toml::from_str::<Condition>("filetype = \"rust\"\nmodifiable = false")
+            .expect("condition is valid");
+        let buffer = api::create_buf(true, false).expect("buffer is created");
+        buffer
+            .call(|()| {
+                let local = OptionValueOpts::builder().scope(OptionScope::Local).build();
+                api::set_option_value("filetype", "rust", &local)?;
+                api::set_option_value("modifiable", false, &local)
+            })
+            .expect("options are set");
+        assert!(condition.matches(&buffer).expect("buffer is readable"));
+        assert!(!condition
+            .matches(&Buffer::current())
+            .expect("buffer is readable"));
+    }
+
+    #[test]
+    fn overlaps_compare_events_and_guards() {
+        let condition = |source| toml::from_str::<Condition>(source).expect("condition is valid");
+        let rust = condition("filetype = \"rust\"");
+        assert!(rust.overlaps(&condition("background = \"dark\"")));
+        assert!(rust.overlaps(&condition("has = \"wsl\"")));
+        assert!(!rust.overlaps(&condition("filetype = \"lua\"")));
+        assert!(!rust.overlaps(&condition("on = \"UIEnter\"")));
+        assert!(condition("on = [\"UIEnter\", \"VimEnter\"]")
+            .overlaps(&condition("on = [\"VimEnter\", \"UIEnter\"]")));
+        for (guard, other) in [
+            ("has = \"wsl\"", "has = \"mac\""),
+            ("when = \"vim.g.a\"", "when = \"vim.g.b\""),
+            ("has_module = \"a\"", "has_module = \"b\""),
+            ("nvim_version = \">=0.10\"", "nvim_version = \"<0.10\""),
+        ] {
+            assert!(condition(guard).overlaps(&condition(guard)));
+            assert!(!condition(guard).overlaps(&condition(other)));
+        }
+    }
+}
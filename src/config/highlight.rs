@@ -0,0 +1,73 @@
+use serde::Deserialize;
+
+use crate::*;
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum HighlightDeserializer {
+    /// Links to this group.
+    Link(String),
+    Attributes(Highlight),
+}
+
+impl From<HighlightDeserializer> for Highlight {
+    fn from(d: HighlightDeserializer) -> Self {
+        match d {
+            HighlightDeserializer::Link(link) => Self {
+                link: Some(link),
+                ..Self::default()
+            },
+            HighlightDeserializer::Attributes(highlight) => highlight,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Default, Clone)]
+#[serde(default)]
+pub struct Highlight {
+    fg: Option<String>,
+    bg: Option<String>,
+    /// Color of underlines and undercurls.
+    sp: Option<String>,
+    blend: Option<u8>,
+    bold: bool,
+    italic: bool,
+    underline: bool,
+    undercurl: bool,
+    strikethrough: bool,
+    reverse: bool,
+    nocombine: bool,
+    link: Option<String>,
+    /// Only set the group if it is not yet defined.
+    default: bool,
+}
+
+impl Highlight {
+    pub fn apply(&self, name: &str) -> ApiResult {
+        let mut opts = SetHighlightOpts::builder();
+        if let Some(fg) = &self.fg {
+            opts.foreground(fg);
+        }
+        if let Some(bg) = &self.bg {
+            opts.background(bg);
+        }
+        if let Some(sp) = &self.sp {
+            opts.special(sp);
+        }
+        if let Some(blend) = self.blend {
+            opts.blend(blend);
+        }
+        if let Some(link) = &self.link {
+            opts.link(link);
+        }
+        opts.bold(self.bold)
+            .italic(self.italic)
+            .underline(self.underline)
+            .undercurl(self.undercurl)
+            .strikethrough(self.strikethrough)
+            .reverse(self.reverse)
+            .nocombine(self.nocombine)
+            .default(self.default);
+        api::set_hl(0, name, &opts.build())
+    }
+}
@@ -1,65 +1,592 @@
-use oxi::api::{types::Mode, Buffer};
-use serde::Deserialize;
+use std::{fmt, slice};
+
+use oxi::api::types::Mode;
+use serde::{
+    de::{
+        self,
+        value::{MapAccessDeserializer, SeqAccessDeserializer},
+        IntoDeserializer, MapAccess, SeqAccess, Unexpected, Visitor,
+    },
+    Deserialize, Deserializer,
+};
 use serde_with::{flattened_maybe, serde_as, OneOrMany};
-use smart_default::SmartDefault;
 
 use crate::*;
 
+/// Flags are optional to tell apart ones not set, which are filled from `defaults`.
 #[serde_as]
-#[derive(Debug, Deserialize, SmartDefault, Clone)]
+#[derive(Debug, Deserialize, Default, Clone)]
 #[serde(default)]
 pub struct Keys {
     #[serde_as(deserialize_as = "OneOrMany<_>")]
     modes: Vec<Mode>,
-    #[default = true]
-    recursive: bool,
-    command: bool,
-    lua: bool,
-    silent: bool,
-    unique: bool,
-    expression: bool,
-    leader: String,
+    /// Defaults to `true`.
+    recursive: Option<bool>,
+    command: Option<bool>,
+    lua: Option<bool>,
+    silent: Option<bool>,
+    nowait: Option<bool>,
+    unique: Option<bool>,
+    expression: Option<bool>,
+    leader: Option<Leader>,
+    /// Prefix for a group of mappings, composed between `leader` and each lhs.
+    prefix: Option<String>,
+    /// Overrides whether the mappings are buffer-local, or the buffer they are local to.
+    buffer: Option<BufferTarget>,
+    /// Description of the mappings, shown by `:map`.
+    desc: Option<String>,
+    /// Notes on the block, ignored.
+    #[serde(rename = "description")]
+    _description: Option<String>,
     #[serde(flatten)]
-    mappings_: HashMap<String, String>,
-    mappings: HashMap<String, String>,
+    mappings_: HashMap<String, KeymapRhs>,
+    mappings: HashMap<String, KeymapRhs>,
 }
 flattened_maybe!(deserialize_mappings, "mappings");
 
+#[serde_as]
+#[derive(Debug, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum KeymapRhs {
+    Single(String),
+    /// Run one after the other, each wrapped on its own for `command` and `lua`.
+    Sequence(Vec<String>),
+    /// A mapping with its own `modes`, overriding the ones of the block.
+    WithModes {
+        #[serde_as(deserialize_as = "OneOrMany<_>")]
+        rhs: Vec<String>,
+        #[serde(alias = "mode")]
+        #[serde_as(deserialize_as = "OneOrMany<_>")]
+        modes: Vec<Mode>,
+    },
+}
+
+impl KeymapRhs {
+    fn parts(&self) -> &[String] {
+        match self {
+            KeymapRhs::Single(rhs) => slice::from_ref(rhs),
+            KeymapRhs::Sequence(rhs) | KeymapRhs::WithModes { rhs, .. } => rhs,
+        }
+    }
+
+    /// Whether the mapping triggers a `<Plug>` mapping, which only works when remapping.
+    fn is_plug(&self) -> bool {
+        self.parts()
+            .iter()
+            .any(|part| part.to_ascii_lowercase().contains("<plug>"))
+    }
+
+    fn modes<'a>(&'a self, default: &'a [Mode]) -> &'a [Mode] {
+        match self {
+            KeymapRhs::WithModes { modes, .. } => modes,
+            _ => default,
+        }
+    }
+}
+
+/// Warning for a `<Plug>` mapping in a block with `recursive = false`.
+pub fn nonrecursive_plug_warning(lhs: &str) -> String {
+    format!(
+        "`{lhs}` maps to `<Plug>` with `recursive = false`, it is mapped recursively as it would \
+         not work otherwise"
+    )
+}
+
+/// Whether `rhs` already runs a command through `<Cmd>`.
+fn is_command(rhs: &str) -> bool {
+    rhs.get(..5)
+        .is_some_and(|cmd| cmd.eq_ignore_ascii_case("<cmd>"))
+}
+
+fn parse_mode(mode: &str) -> Result<Mode, serde::de::value::Error> {
+    Mode::deserialize(mode.into_deserializer())
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum Leader {
+    /// Prefix for all modes, `leader` and `localleader` expand to `<Leader>` and
+    /// `<LocalLeader>`.
+    All(String),
+    /// Prefix by mode, modes without an entry have no prefix.
+    PerMode(HashMap<String, String>),
+}
+
+impl Default for Leader {
+    fn default() -> Self {
+        Self::All(String::new())
+    }
+}
+
+impl Leader {
+    fn for_mode(&self, mode: Mode) -> &str {
+        let leader = match self {
+            Leader::All(leader) => leader.as_str(),
+            Leader::PerMode(leaders) => leaders
+                .iter()
+                .find(|(leader_mode, _)| parse_mode(leader_mode).ok() == Some(mode))
+                .map_or("", |(_, leader)| leader.as_str()),
+        };
+        match leader {
+            "leader" => "<Leader>",
+            "localleader" => "<LocalLeader>",
+            leader => leader,
+        }
+    }
+}
+
+/// A single mapping with its own flags, like the arguments of `vim.keymap.set`.
+#[serde_as]
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KeyMapping {
+    lhs: String,
+    rhs: KeymapRhs,
+    #[serde(default, alias = "mode")]
+    #[serde_as(deserialize_as = "OneOrMany<_>")]
+    modes: Vec<Mode>,
+    desc: Option<String>,
+    recursive: Option<bool>,
+    command: Option<bool>,
+    lua: Option<bool>,
+    silent: Option<bool>,
+    nowait: Option<bool>,
+    unique: Option<bool>,
+    expression: Option<bool>,
+    buffer: Option<BufferTarget>,
+    leader: Option<Leader>,
+}
+
+impl From<KeyMapping> for Keys {
+    fn from(mapping: KeyMapping) -> Self {
+        Self {
+            modes: mapping.modes,
+            recursive: mapping.recursive,
+            command: mapping.command,
+            lua: mapping.lua,
+            silent: mapping.silent,
+            nowait: mapping.nowait,
+            unique: mapping.unique,
+            expression: mapping.expression,
+            buffer: mapping.buffer,
+            leader: mapping.leader,
+            desc: mapping.desc,
+            mappings: HashMap::from([(mapping.lhs, mapping.rhs)]),
+            ..Self::default()
+        }
+    }
+}
+
+/// Deserialized by the shape of the value instead of `#[serde(untagged)]`, see [`KeysVisitor`].
+#[derive(Debug)]
+pub enum KeysDeserializer {
+    /// One `mode lhs rhs` mapping per line.
+    Compact(String),
+    /// Before `Block`, which would take `lhs` and `rhs` as mappings.
+    Mapping(KeyMapping),
+    Block(Keys),
+}
+
+impl TryFrom<KeysDeserializer> for Vec<Keys> {
+    type Error = String;
+
+    fn try_from(d: KeysDeserializer) -> Result<Self, Self::Error> {
+        match d {
+            KeysDeserializer::Compact(lines) => lines
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .map(Keys::from_compact)
+                .collect(),
+            KeysDeserializer::Mapping(mapping) => Ok(vec![mapping.into()]),
+            KeysDeserializer::Block(keys) => Ok(vec![keys]),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum KeysListDeserializer {
+    One(KeysDeserializer),
+    List(Vec<KeysDeserializer>),
+}
+
+/// Picks the form of `keys` by the shape of the value: a string is `Compact`, a table with `lhs`
+/// a `Mapping` and any other table a `Block`. Unlike `#[serde(untagged)]` this reports why the
+/// form that was meant is invalid.
+struct KeysVisitor {
+    /// Whether a list of the forms is accepted.
+    list: bool,
+}
+
+impl<'de> Visitor<'de> for KeysVisitor {
+    type Value = KeysListDeserializer;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        if self.list {
+            formatter.write_str("a string, a table of mappings or a list of them")
+        } else {
+            formatter.write_str("a string or a table of mappings")
+        }
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+        Ok(KeysListDeserializer::One(KeysDeserializer::Compact(
+            v.to_owned(),
+        )))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+        // buffered to look for `lhs` before choosing the form
+        let map = serde_yaml::Mapping::deserialize(MapAccessDeserializer::new(map))?;
+        let keys = if map.contains_key(&"lhs".into()) {
+            // unknown fields of a mapping are rejected, so comments are dropped before
+            let map = map
+                .into_iter()
+                .filter(|(key, _)| !key.as_str().is_some_and(is_comment))
+                .collect();
+            KeyMapping::deserialize(serde_yaml::Value::Mapping(map)).map(KeysDeserializer::Mapping)
+        } else {
+            Keys::deserialize(serde_yaml::Value::Mapping(map)).map(KeysDeserializer::Block)
+        };
+        keys.map(KeysListDeserializer::One)
+            .map_err(de::Error::custom)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<Self::Value, A::Error> {
+        if !self.list {
+            return Err(de::Error::invalid_type(Unexpected::Seq, &self));
+        }
+        Vec::deserialize(SeqAccessDeserializer::new(seq)).map(KeysListDeserializer::List)
+    }
+}
+
+impl<'de> Deserialize<'de> for KeysDeserializer {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match deserializer.deserialize_any(KeysVisitor { list: false })? {
+            KeysListDeserializer::One(keys) => Ok(keys),
+            KeysListDeserializer::List(_) => unreachable!("rejected by the visitor"),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for KeysListDeserializer {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(KeysVisitor { list: true })
+    }
+}
+
+impl TryFrom<KeysListDeserializer> for Vec<Keys> {
+    type Error = String;
+
+    fn try_from(d: KeysListDeserializer) -> Result<Self, Self::Error> {
+        match d {
+            KeysListDeserializer::One(keys) => keys.try_into(),
+            KeysListDeserializer::List(list) => {
+                list.into_iter().map(Vec::try_from).flatten_ok().collect()
+            }
+        }
+    }
+}
+
 impl Keys {
+    /// A single non-recursive mapping, the way `:noremap` creates it.
+    pub fn mapping(mode: Mode, lhs: impl Into<String>, rhs: impl Into<String>) -> Self {
+        Self {
+            modes: vec![mode],
+            recursive: Some(false),
+            mappings: HashMap::from([(lhs.into(), KeymapRhs::Single(rhs.into()))]),
+            ..Self::default()
+        }
+    }
+
+    fn from_compact(line: &str) -> Result<Self, String> {
+        let invalid = || format!("expected `mode lhs rhs`, found `{line}`");
+        let (mode, rest) = line.split_once(char::is_whitespace).ok_or_else(invalid)?;
+        let (lhs, rhs) = rest
+            .trim_start()
+            .split_once(char::is_whitespace)
+            .ok_or_else(invalid)?;
+        let mode =
+            parse_mode(mode).map_err(|error| format!("invalid mode in `{line}`: {error}"))?;
+        Ok(Self {
+            modes: vec![mode],
+            mappings: HashMap::from([(
+                lhs.to_owned(),
+                KeymapRhs::Single(rhs.trim_start().to_owned()),
+            )]),
+            ..Self::default()
+        })
+    }
+
+    /// Every declared mapping as `(mode, lhs, rhs)`, in the modes of the mapping or the block.
+    fn entries(&self) -> impl Iterator<Item = (Mode, &str, &KeymapRhs)> {
+        self.mappings
+            .iter()
+            .chain(self.mappings_.iter())
+            .flat_map(move |(lhs, rhs)| {
+                rhs.modes(&self.modes)
+                    .iter()
+                    .map(move |&mode| (mode, lhs.as_str(), rhs))
+            })
+    }
+
+    pub fn mapping_count(&self) -> usize {
+        self.entries().count()
+    }
+
+    /// Whether mappings are declared that are not mapped in any mode.
+    pub fn lacks_modes(&self) -> bool {
+        self.mappings
+            .values()
+            .chain(self.mappings_.values())
+            .any(|rhs| rhs.modes(&self.modes).is_empty())
+    }
+
+    /// Mappings to `<Plug>` in a block with `recursive = false`, which are mapped recursively
+    /// anyway.
+    pub fn nonrecursive_plugs(&self) -> impl Iterator<Item = &str> {
+        self.mappings
+            .iter()
+            .chain(self.mappings_.iter())
+            .filter(|(_, rhs)| self.recursive == Some(false) && rhs.is_plug())
+            .map(|(lhs, _)| lhs.as_str())
+    }
+
+    pub fn runs_lua(&self) -> bool {
+        self.lua.unwrap_or_default()
+    }
+
+    /// Fills the flags not set on this block from `defaults`.
+    pub fn apply_defaults(&mut self, defaults: &Self) {
+        if self.modes.is_empty() {
+            self.modes = defaults.modes.clone();
+        }
+        for (flag, default) in [
+            (&mut self.recursive, defaults.recursive),
+            (&mut self.command, defaults.command),
+            (&mut self.lua, defaults.lua),
+            (&mut self.silent, defaults.silent),
+            (&mut self.nowait, defaults.nowait),
+            (&mut self.unique, defaults.unique),
+            (&mut self.expression, defaults.expression),
+            (&mut self.buffer, defaults.buffer),
+        ] {
+            if flag.is_none() {
+                *flag = default;
+            }
+        }
+        if self.leader.is_none() {
+            self.leader = defaults.leader.clone();
+        }
+        if self.prefix.is_none() {
+            self.prefix = defaults.prefix.clone();
+        }
+    }
+
+    pub fn default_buffer(&mut self, buffer: bool) {
+        self.buffer.get_or_insert(BufferTarget::Local(buffer));
+    }
+
+    fn lhs(&self, mode: Mode, lhs: &str) -> String {
+        format!(
+            "{}{}{lhs}",
+            self.leader
+                .as_ref()
+                .map_or("", |leader| leader.for_mode(mode)),
+            self.prefix.as_deref().unwrap_or_default()
+        )
+    }
+
+    /// Every mapping as `(mode, lhs, rhs)` the way it is mapped, along with `buffer`.
+    pub fn mappings(&self) -> impl Iterator<Item = (Mode, String, String, Option<bool>)> + '_ {
+        self.entries().map(|(mode, lhs, rhs)| {
+            (
+                mode,
+                self.lhs(mode, lhs),
+                self.rhs(rhs),
+                self.buffer.map(BufferTarget::is_local),
+            )
+        })
+    }
+
+    /// Wraps each part of `rhs` according to `lua`, `command` and `expression`.
+    ///
+    /// | `lua` | `command` | `expression` | part becomes                          |
+    /// |-------|-----------|--------------|---------------------------------------|
+    /// | yes   | any       | no           | `<CMD>lua part()<CR>`                 |
+    /// | yes   | any       | yes          | `luaeval('part()')`, returning keys   |
+    /// | no    | yes       | no           | `<CMD>part<CR>`                       |
+    /// | no    | any       | any          | `part` as is                          |
+    ///
+    /// A `()` call is only appended if `part` does not end in `)`. Parts of expressions are
+    /// concatenated with `.`.
+    fn rhs(&self, rhs: &KeymapRhs) -> String {
+        let parts = rhs.parts().iter().map(|rhs| {
+            let call = if rhs.ends_with(')') { "" } else { "()" };
+            match (
+                self.runs_lua(),
+                self.command.unwrap_or_default(),
+                self.expression.unwrap_or_default(),
+            ) {
+                (true, _, true) => format!("luaeval('{}{call}')", rhs.replace('\'', "''")),
+                (true, _, false) => format!("<CMD>lua {rhs}{call}<CR>"),
+                // already a command mapping, e.g. `<Cmd>write<CR>`
+                (false, true, false) if is_command(rhs) => rhs.clone(),
+                (false, true, false) => format!("<CMD>{rhs}<CR>"),
+                (false, _, _) => rhs.clone(),
+            }
+        });
+        if self.expression.unwrap_or_default() {
+            parts.format(" . ").to_string()
+        } else {
+            parts.collect()
+        }
+    }
+
     pub fn apply(&self, buffer: bool) -> ApiResult {
-        for mode in &self.modes {
-            for (lhs, rhs) in self.mappings.iter().chain(self.mappings_.iter()) {
-                let cmd;
-                let set_keymap: &fn(Mode, &str, &str, SetKeymapOpts) -> ApiResult<()> = &if buffer {
-                    |a, b, c, d| Buffer::current().set_keymap(a, b, c, &d)
-                } else {
-                    |a, b, c, d| api::set_keymap(a, b, c, &d)
-                };
-
-                set_keymap(
-                    *mode,
-                    &(self.leader.clone() + lhs),
-                    if self.lua {
-                        cmd = format!(
-                            "<CMD>lua {rhs}{}<CR>",
-                            if rhs.ends_with(')') { "" } else { "()" }
-                        );
-                        &cmd
-                    } else if self.command {
-                        cmd = format!("<CMD>{rhs}<CR>");
-                        &cmd
-                    } else {
-                        rhs
-                    },
-                    SetKeymapOpts::builder()
-                        .noremap(!self.recursive)
-                        .silent(self.silent)
-                        .unique(self.unique)
-                        .expr(self.expression)
-                        .build(),
+        let target = self.buffer.unwrap_or(BufferTarget::Local(buffer));
+        for (mode, lhs, rhs) in self.entries() {
+            // `<Plug>` mappings do nothing unless remapped
+            let plug = rhs.is_plug();
+            let recursive = self.recursive.unwrap_or(true) || plug;
+            let rhs = self.rhs(rhs);
+            let lhs = self.lhs(mode, lhs);
+            if plug && self.recursive == Some(false) {
+                api::notify(
+                    &nonrecursive_plug_warning(&lhs),
+                    LogLevel::Warn,
+                    &NotifyOpts::default(),
                 )?;
             }
+            let mut opts = SetKeymapOpts::builder();
+            opts.noremap(!recursive)
+                .silent(self.silent.unwrap_or_default())
+                .nowait(self.nowait.unwrap_or_default())
+                .unique(self.unique.unwrap_or_default())
+                .expr(self.expression.unwrap_or_default())
+                // keys returned from lua are not in the notation vimscript expressions
+                // use for special keys
+                .replace_keycodes(self.runs_lua() && self.expression.unwrap_or_default());
+            if let Some(desc) = &self.desc {
+                opts.desc(desc);
+            }
+            let opts = opts.build();
+            let mapped = if let Some(mut buffer) = target.buffer() {
+                buffer.set_keymap(mode, &lhs, &rhs, &opts)
+            } else {
+                api::set_keymap(mode, &lhs, &rhs, &opts).map(|()| record_keymap(mode, &lhs))
+            };
+            // e.g. a `unique` mapping that already exists, which should not stop the others
+            if let Err(error) = mapped {
+                log_error!("Error while mapping `{lhs}` to `{rhs}` in mode {mode:?}: \n{error}");
+            }
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(source: &str) -> Result<Vec<Keys>, String> {
+        #[derive(Deserialize)]
+        struct Wrapper {
+            keys: KeysListDeserializer,
+        }
+        let wrapper: Wrapper = toml::from_str(source).map_err(|error| error.to_string())?;
+        wrapper.keys.try_into()
+    }
+
+    #[test]
+    fn forms_by_shape() {
+        assert_eq!(keys("keys = \"n a b\"").expect("compact is valid").len(), 1);
+        assert_eq!(
+            keys("keys = { lhs = \"a\", rhs = \"b\" }")
+                .expect("mapping is valid")
+                .len(),
+            1
+        );
+        assert_eq!(
+            keys("[[keys]]\na = \"b\"\n[[keys]]\nlhs = \"c\"\nrhs = \"d\"")
+                .expect("list is valid")
+                .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn mappings_ignore_comments() {
+        assert_eq!(
+            keys("keys = { lhs = \"a\", rhs = \"b\", description = \"c\", _note = \"d\" }")
+                .expect("comments are ignored")
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn inner_errors_are_reported() {
+        let error =
+            keys("keys = { lhs = \"a\", rhs = \"b\", slient = true }").expect_err("unknown field");
+        assert!(error.contains("unknown field `slient`"), "{error}");
+        let error = keys("keys = { silent = \"yes\", a = \"b\" }").expect_err("invalid flag");
+        assert!(error.contains("invalid type"), "{error}");
+        let error = keys("keys = 1").expect_err("invalid form");
+        assert!(
+            error.contains("a string, a table of mappings or a list of them"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn mappings_have_their_own_leader() {
+        let keys = keys(
+            "keys = [{ modes = \"n\", leader = \"leader\", lhs = \"w\", rhs = \"<Cmd>w<CR>\" },\
+             { modes = \"n\", lhs = \"q\", rhs = \"<Cmd>q<CR>\" }]",
+        )
+        .expect("mappings are valid");
+        let lhs = keys
+            .iter()
+            .flat_map(Keys::mappings)
+            .map(|(_, lhs, ..)| lhs)
+            .collect_vec();
+        assert_eq!(lhs, ["<Leader>w", "q"]);
+    }
+
+    #[nvim_oxi::test]
+    fn nonrecursive_plugs_are_remapped() {
+        let keys = keys("keys = { modes = \"n\", recursive = false, gx = \"<Plug>(test)\" }")
+            .expect("block is valid");
+        for keys in &keys {
+            keys.apply(false).expect("mapping is applied");
+        }
+        let mapping = api::get_keymap(Mode::Normal)
+            .find(|mapping| mapping.lhs == "gx")
+            .expect("gx is mapped");
+        assert!(!mapping.noremap);
+    }
+
+    #[nvim_oxi::test]
+    fn failing_mappings_do_not_stop_the_others() {
+        api::set_keymap(Mode::Normal, "gX", "gx", &SetKeymapOpts::default())
+            .expect("existing mapping is set");
+        let keys = keys(
+            "keys = { modes = \"n\", unique = true, gX = \"<Cmd>w<CR>\", gY = \"<Cmd>q<CR>\" }",
+        )
+        .expect("block is valid");
+        for keys in &keys {
+            keys.apply(false).expect("mappings are applied");
+        }
+        let errors = logged_messages();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("Error while mapping `gX` to `<Cmd>w<CR>` in mode Normal"));
+        let mapped = |lhs: &str| {
+            api::get_keymap(Mode::Normal)
+                .find(|mapping| mapping.lhs == lhs)
+                .and_then(|mapping| mapping.rhs)
+        };
+        assert_eq!(mapped("gX").as_deref(), Some("gx"));
+        assert_eq!(mapped("gY").as_deref(), Some("<Cmd>q<CR>"));
+    }
+}
@@ -1,9 +1,16 @@
-use std::{collections::HashMap, fs, path::Path};
+use std::{
+    collections::{HashMap, HashSet},
+    fs, iter, mem,
+    path::{Path, PathBuf},
+};
 
 use merge::Merge;
-use oxi::{self as oxi, api::create_autocmd};
+use oxi::{
+    self as oxi,
+    api::{create_autocmd, types::Mode, Buffer},
+};
 use serde::Deserialize;
-use serde_with::{serde_as, FromInto, OneOrMany};
+use serde_with::{serde_as, FromInto, OneOrMany, TryFromInto};
 
 mod condition;
 pub use condition::*;
@@ -11,6 +18,18 @@ mod set;
 pub use set::*;
 mod keys;
 pub use keys::*;
+mod command;
+pub use command::*;
+mod highlight;
+pub use highlight::*;
+mod abbreviation;
+pub use abbreviation::*;
+mod variable;
+pub use variable::*;
+mod revert;
+pub use revert::*;
+mod builder;
+pub use builder::*;
 
 use crate::*;
 
@@ -25,6 +44,10 @@ pub struct AutoCommand {
     #[serde(default)]
     #[serde_as(deserialize_as = "OneOrMany<_>")]
     lua: Vec<String>,
+    /// Keys fed to Neovim when triggered, key notation like `<Esc>` is translated.
+    #[serde(default)]
+    #[serde_as(deserialize_as = "OneOrMany<_>")]
+    keys: Vec<String>,
     pattern: Option<String>,
     // #[serde(default)]
     // event: HashMap<String, String>,
@@ -32,93 +55,1196 @@ pub struct AutoCommand {
     // silent: bool,
 }
 
+impl AutoCommand {
+    /// Runs the ex command `cmd` on `triggers`.
+    pub fn command(triggers: Vec<String>, cmd: impl Into<String>) -> Self {
+        Self {
+            triggers,
+            cmd: vec![cmd.into()],
+            lua: Vec::new(),
+            keys: Vec::new(),
+            pattern: None,
+        }
+    }
+
+    pub fn apply(&self, group: Option<u32>) -> ApiResult {
+        for cmd in self.cmd.iter().cloned().chain(
+            self.lua
+                .iter()
+                .map(|lua| format!("lua {lua}{}", if lua.ends_with(')') { "" } else { "()" })),
+        ) {
+            let mut opts = CreateAutocmdOpts::builder();
+            opts.patterns(self.pattern.iter().map(AsRef::as_ref))
+                .command(cmd.as_str());
+            if let Some(group) = group {
+                opts.group(group);
+            }
+            create_autocmd(self.triggers.iter().map(AsRef::as_ref), &opts.build())?;
+        }
+        for keys in &self.keys {
+            let keys = termcodes(keys);
+            let mut opts = CreateAutocmdOpts::builder();
+            opts.patterns(self.pattern.iter().map(AsRef::as_ref))
+                .callback(move |_: AutocmdCallbackArgs| -> Result<bool> {
+                    api::feedkeys(&keys, "n", false);
+                    Ok(false)
+                });
+            if let Some(group) = group {
+                opts.group(group);
+            }
+            create_autocmd(self.triggers.iter().map(AsRef::as_ref), &opts.build())?;
+        }
+        Ok(())
+    }
+}
+
+/// Autocommands to remove, matching all of the given fields.
+#[serde_as]
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct ClearAutoCommands {
+    #[serde_as(deserialize_as = "OneOrMany<_>")]
+    events: Vec<String>,
+    #[serde_as(deserialize_as = "OneOrMany<_>")]
+    patterns: Vec<String>,
+    group: Option<String>,
+}
+
+impl ClearAutoCommands {
+    /// Whether no field is given, which would clear every autocommand outside of a group.
+    pub fn is_unrestricted(&self) -> bool {
+        self.events.is_empty() && self.patterns.is_empty() && self.group.is_none()
+    }
+
+    pub fn apply(&self) -> ApiResult {
+        if self.is_unrestricted() {
+            log_error!("Not clearing autocommands without `events`, `patterns` or `group`");
+            return Ok(());
+        }
+        let mut get = GetAutocmdsOpts::builder();
+        let mut clear = ClearAutocmdsOpts::builder();
+        if !self.events.is_empty() {
+            get.events(self.events.iter().map(AsRef::as_ref));
+            clear.events(self.events.iter().map(AsRef::as_ref));
+        }
+        if !self.patterns.is_empty() {
+            get.patterns(self.patterns.iter().map(AsRef::as_ref));
+            clear.patterns(self.patterns.iter().map(AsRef::as_ref));
+        }
+        if let Some(group) = &self.group {
+            get.group(group.as_str());
+            clear.group(group.as_str());
+        }
+        // without a group only autocommands outside of groups are cleared
+        let cleared = api::get_autocmds(&get.build())?
+            .filter(|autocmd| self.group.is_some() || autocmd.group.is_none())
+            .count();
+        api::clear_autocmds(&clear.build())?;
+        api::notify(
+            &format!("Cleared {cleared} autocommand(s)"),
+            LogLevel::Debug,
+            &NotifyOpts::default(),
+        )?;
+        Ok(())
+    }
+}
+
+/// Parts of a config that can be applied on their own, in the order [`Config::apply`] applies
+/// them.
+#[derive(Debug, Clone, Copy)]
+pub enum Section {
+    Keys,
+    Abbreviations,
+    Set,
+    Vim,
+    Highlight,
+    Variables,
+    Commands,
+    AutoCommands,
+}
+
+impl Section {
+    pub const ALL: [Self; 8] = [
+        Self::Keys,
+        Self::Abbreviations,
+        Self::Set,
+        Self::Vim,
+        Self::Highlight,
+        Self::Variables,
+        Self::Commands,
+        Self::AutoCommands,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Keys => "keys",
+            Self::Abbreviations => "abbreviations",
+            Self::Set => "set",
+            Self::Vim => "vim",
+            Self::Highlight => "highlight",
+            Self::Variables => "variables",
+            Self::Commands => "commands",
+            Self::AutoCommands => "autocmds",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|section| section.name() == name)
+    }
+
+    /// Whether conditional configs apply the section only the first time they match after
+    /// loading, as applying it again would run scripts again and pile up autocommands.
+    pub fn is_once_per_load(self) -> bool {
+        matches!(self, Self::Vim | Self::AutoCommands)
+    }
+}
+
+/// Formats a parse error, pointing at the 1-based `location` in `source` if known.
+fn parse_error(path: &Path, source: &str, location: Option<(usize, usize)>, error: &str) -> String {
+    let Some((line, column)) = location else {
+        return format!("error while parsing {}: {error}", path.display());
+    };
+    let snippet = source
+        .lines()
+        .nth(line.saturating_sub(1))
+        .unwrap_or_default();
+    format!(
+        "error while parsing {}:{line}:{column}: {error}\n    {snippet}\n    {:>column$}",
+        path.display(),
+        "^"
+    )
+}
+
+fn extend<T>(left: &mut HashMap<String, T>, right: HashMap<String, T>) {
+    left.extend(right);
+}
+
+fn extend_sections(left: &mut HashMap<String, Config>, right: HashMap<String, Config>) {
+    for (name, section) in right {
+        left.entry(name).or_default().merge_from(section);
+    }
+}
+
+fn extend_augroups(
+    left: &mut HashMap<String, Vec<AutoCommand>>,
+    right: HashMap<String, Vec<AutoCommand>>,
+) {
+    for (group, auto_commands) in right {
+        left.entry(group).or_default().extend(auto_commands);
+    }
+}
+
+/// Flags filled into every block of the same file that does not set them.
+#[derive(Debug, Deserialize, Default, Clone)]
+#[serde(default)]
+pub struct Defaults {
+    keys: Keys,
+    set: SetModifiers,
+}
+
+/// How a config's `set` and `keys` are combined with those of configs merged before it.
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MergeMode {
+    #[default]
+    Append,
+    /// Drops the `set` and `keys` of earlier configs.
+    Replace,
+}
+
+/// Whether keymaps or options are buffer-local, either to the current buffer or the one with a
+/// number, e.g. from `nvim_create_buf`.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(untagged)]
+pub enum BufferTarget {
+    Local(bool),
+    Number(i32),
+}
+
+impl BufferTarget {
+    pub fn is_local(self) -> bool {
+        match self {
+            BufferTarget::Local(local) => local,
+            BufferTarget::Number(_) => true,
+        }
+    }
+
+    /// The buffer to apply in, `None` for global.
+    pub fn buffer(self) -> Option<Buffer> {
+        match self {
+            BufferTarget::Local(local) => local.then(Buffer::current),
+            BufferTarget::Number(number) => Some(Buffer::from(number)),
+        }
+    }
+}
+
+/// Format of a config's source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// YAML, also used for JSON.
+    Yaml,
+    Toml,
+}
+
+impl Format {
+    pub fn from_extension(ext: &str) -> Result<Self, String> {
+        match ext.to_ascii_lowercase().as_str() {
+            "json" | "yml" | "yaml" => Ok(Self::Yaml),
+            "toml" => Ok(Self::Toml),
+            ext => Err(format!("unsupported config format `{ext}`")),
+        }
+    }
+}
+
 #[serde_as]
 #[derive(Debug, Deserialize, Default, Merge, Clone)]
 #[serde(default)]
 pub struct Config {
-    // TODO investigate if `or` is the right way to interpret multiple conditions
+    /// Alternatives, the config applies when any of them matches.
     #[merge(skip)]
     pub conditions: Vec<Condition>,
     #[merge(strategy = merge::vec::append)]
+    #[serde_as(deserialize_as = "TryFromInto<KeysListDeserializer>")]
     pub keys: Vec<Keys>,
     #[merge(strategy = merge::vec::append)]
-    #[serde_as(deserialize_as = "FromInto<SetsDeserializer>")]
+    #[serde_as(deserialize_as = "OneOrMany<_>")]
+    pub abbreviations: Vec<Abbreviations>,
+    #[merge(strategy = merge::vec::append)]
+    #[serde_as(deserialize_as = "TryFromInto<SetsDeserializer>")]
     pub set: Vec<Set>,
     #[merge(strategy = merge::vec::append)]
     #[serde_as(deserialize_as = "OneOrMany<_>")]
     pub auto_commands: Vec<AutoCommand>,
+    /// Autocommands removed before creating the ones of this config.
+    #[merge(strategy = merge::vec::append)]
+    #[serde_as(deserialize_as = "OneOrMany<_>")]
+    pub clear_auto_commands: Vec<ClearAutoCommands>,
+    /// Autocommands by the group they are created in, the groups are cleared when applied.
+    #[merge(strategy = extend_augroups)]
+    pub augroups: HashMap<String, Vec<AutoCommand>>,
+    /// User commands by their name.
+    #[merge(strategy = extend)]
+    #[serde_as(deserialize_as = "HashMap<_, FromInto<UserCommandDeserializer>>")]
+    pub commands: HashMap<String, UserCommand>,
+    /// Highlight groups by their name.
+    #[merge(strategy = extend)]
+    #[serde(alias = "hl")]
+    #[serde_as(deserialize_as = "HashMap<_, FromInto<HighlightDeserializer>>")]
+    pub highlight: HashMap<String, Highlight>,
+    /// Vimscript run after `set`, for what has no section of its own.
+    #[merge(strategy = merge::vec::append)]
+    #[serde_as(deserialize_as = "OneOrMany<_>")]
+    pub vim: Vec<String>,
+    /// Variables of the current window at the time of applying.
+    #[merge(strategy = extend)]
+    pub w: HashMap<String, SetValue>,
+    /// Variables of the current tabpage at the time of applying.
+    #[merge(strategy = extend)]
+    pub t: HashMap<String, SetValue>,
+    /// Applies everything in this file buffer-locally (or globally) regardless of conditions,
+    /// unless overridden on an item.
+    #[merge(skip)]
+    pub buffer: Option<bool>,
+    /// Config files merged into this one, relative to its directory.
+    #[merge(skip)]
+    #[serde_as(deserialize_as = "OneOrMany<_>")]
+    pub include: Vec<String>,
+    #[merge(skip)]
+    pub defaults: Defaults,
+    /// Names usable in `set` of the same file for multiple options at once.
+    #[merge(skip)]
+    #[serde_as(deserialize_as = "HashMap<_, OneOrMany<_>>")]
+    pub aliases: HashMap<String, Vec<String>>,
+    /// Set to `false` to keep the file without it contributing anything.
+    #[merge(skip)]
+    pub enabled: Option<bool>,
+    #[merge(skip)]
+    pub merge: MergeMode,
+    /// Configs with a higher priority are merged after those with a lower one, configs of the
+    /// same priority in the order they are found.
+    #[merge(skip)]
+    pub priority: i32,
+    /// Configs for buffers of a filetype, e.g. `[filetype.rust]`, under the `conditions` of this
+    /// one.
+    #[serde(rename = "filetype")]
+    #[merge(strategy = extend_sections)]
+    pub filetypes: HashMap<String, Config>,
+}
+
+/// The conditions a config with `conditions` is merged under, see
+/// [`Config::merge_into_hashmap`], checking the ones made once when loading.
+fn resolve_conditions(conditions: &[Condition]) -> Vec<Condition> {
+    if conditions.is_empty() {
+        return vec![Condition::default()];
+    }
+    conditions
+        .iter()
+        .cloned()
+        .flat_map(Condition::flatten)
+        .filter_map(Condition::resolve_version)
+        .filter_map(Condition::resolve_features)
+        .filter_map(Condition::resolve_modules)
+        .filter_map(Condition::resolve_when)
+        .flat_map(|condition| {
+            if condition == Condition::default() {
+                vec![condition]
+            } else {
+                condition.into_iter().collect()
+            }
+        })
+        .collect()
 }
 
 impl Config {
-    pub fn merge_into_hashmap(self, hash_map: &mut HashMap<Condition, Self>) {
-        let mut conditions = self
-            .conditions
-            .clone()
-            .into_iter()
-            .flat_map(IntoIterator::into_iter)
-            .peekable();
-        if conditions.peek().is_none() {
-            if let Some(config) = hash_map.get_mut(&Condition::default()) {
-                config.merge(self);
+    /// Merges `other` into `self`, replacing lists if `other` asks for it.
+    pub fn merge_from(&mut self, mut other: Self) {
+        if other.merge == MergeMode::Replace {
+            self.set.clear();
+            self.keys.clear();
+        }
+        self.merge_maps(&mut other);
+        self.merge(other);
+    }
+
+    /// Adds the entries of maps assigned by `self` to those `other` assigns by listing entries,
+    /// so map options accumulate entries across configs.
+    fn merge_maps(&self, other: &mut Self) {
+        for Set(name, operation, value, modifiers) in &mut other.set {
+            let (Operation::Assign, SetValue::Map(map), true) =
+                (&*operation, &mut *value, modifiers.merge_map)
+            else {
+                continue;
+            };
+            let earlier = self
+                .set
+                .iter()
+                .rev()
+                .filter(|Set(earlier, ..)| *earlier == *name)
+                .find_map(|set| match set {
+                    Set(_, Operation::Assign, SetValue::Map(earlier), _) => Some(earlier),
+                    _ => None,
+                });
+            for (key, entry) in earlier.into_iter().flatten() {
+                map.entry(key.clone()).or_insert_with(|| entry.clone());
+            }
+        }
+    }
+
+    pub fn merge_into_hashmap(mut self, hash_map: &mut HashMap<Condition, Self>) {
+        for (filetype, mut section) in mem::take(&mut self.filetypes) {
+            section.conditions = vec![Condition::section(filetype, self.conditions.clone())];
+            section.merge_into_hashmap(hash_map);
+        }
+        for condition in resolve_conditions(&self.conditions) {
+            if let Some(config) = hash_map.get_mut(&condition) {
+                config.merge_from(self.clone());
             } else {
-                hash_map.insert(Condition::default(), self);
-            }
-        } else {
-            for condition in conditions {
-                if let Some(config) = hash_map.get_mut(&condition) {
-                    config.merge(self.clone());
-                } else {
-                    hash_map.insert(condition, self.clone());
+                hash_map.insert(condition, self.clone());
+            }
+        }
+    }
+
+    /// Options assigned different values by `self` and `other` under conditions that can match
+    /// the same buffer, the outcome of which depends on the order the autocommands run in.
+    pub fn conflicts(&self, other: &Self) -> Vec<String> {
+        self.sections()
+            .into_iter()
+            .cartesian_product(other.sections())
+            .flat_map(|((conditions, config), (other_conditions, other))| {
+                config.set_conflicts(&conditions, other, &other_conditions)
+            })
+            .collect()
+    }
+
+    /// `self` and its filetype sections with the conditions they are applied under, see
+    /// [`Config::merge_into_hashmap`].
+    fn sections(&self) -> Vec<(Vec<Condition>, &Self)> {
+        iter::once((self.conditions.clone(), self))
+            .chain(
+                self.filetypes
+                    .iter()
+                    .sorted_by_key(|(filetype, _)| *filetype)
+                    .map(|(filetype, section)| {
+                        (
+                            vec![Condition::section(
+                                filetype.clone(),
+                                self.conditions.clone(),
+                            )],
+                            section,
+                        )
+                    }),
+            )
+            .collect()
+    }
+
+    /// Options assigned different values by the `set` of `self` under `conditions` and of `other`
+    /// under `other_conditions`, if any of those overlap.
+    fn set_conflicts(
+        &self,
+        conditions: &[Condition],
+        other: &Self,
+        other_conditions: &[Condition],
+    ) -> Vec<String> {
+        // configs under the same condition are merged in a fixed order
+        let overlapping: Vec<_> = resolve_conditions(conditions)
+            .into_iter()
+            .cartesian_product(resolve_conditions(other_conditions))
+            .filter(|(condition, other)| condition != other && condition.overlaps(other))
+            .collect();
+        if overlapping.is_empty() {
+            return Vec::new();
+        }
+        let conditions = overlapping
+            .iter()
+            .map(|(condition, other)| format!("({condition}) and ({other})"))
+            .join(", ");
+        let mut conflicts = Vec::new();
+        for Set(name, op, value, _) in &self.set {
+            for Set(other_name, other_op, other_value, _) in &other.set {
+                if *op == Operation::Assign
+                    && *other_op == Operation::Assign
+                    && name == other_name
+                    && value != other_value
+                {
+                    conflicts.push(format!(
+                        "`{name}` is assigned {value:?} and {other_value:?} under overlapping \
+                         conditions: {conditions}"
+                    ));
+                }
+            }
+        }
+        conflicts
+    }
+
+    /// Options assigned more than once with different values, only the last of which takes
+    /// effect, in this config or one of its filetype sections.
+    pub fn assign_conflicts(&self) -> Vec<String> {
+        let mut assigned: Vec<(&str, &SetValue)> = Vec::new();
+        let mut conflicts = Vec::new();
+        for Set(name, op, value, _) in &self.set {
+            if *op != Operation::Assign {
+                continue;
+            }
+            if let Some((_, previous)) = assigned.iter().find(|(n, _)| *n == name) {
+                if *previous != value {
+                    conflicts.push(format!("`{name}` is assigned {previous:?} and {value:?}"));
+                }
+            }
+            assigned.push((name, value));
+        }
+        for (filetype, section) in self
+            .filetypes
+            .iter()
+            .sorted_by_key(|(filetype, _)| *filetype)
+        {
+            conflicts.extend(
+                section
+                    .assign_conflicts()
+                    .into_iter()
+                    .map(|conflict| format!("filetype {filetype}: {conflict}")),
+            );
+        }
+        conflicts
+    }
+
+    /// Keymaps mapped more than once with different rhs, only the last of which takes effect.
+    pub fn keymap_conflicts(&self) -> Vec<String> {
+        let mut mapped: Vec<(Mode, String, String, Option<bool>)> = Vec::new();
+        let mut conflicts = Vec::new();
+        for (mode, lhs, rhs, buffer) in self.keys.iter().flat_map(Keys::mappings) {
+            if let Some((.., previous, _)) = mapped
+                .iter()
+                .find(|(m, l, _, b)| *m == mode && *l == lhs && *b == buffer)
+            {
+                if *previous != rhs {
+                    conflicts.push(format!(
+                        "`{lhs}` in mode {mode:?} is mapped to `{previous}` and `{rhs}`"
+                    ));
+                }
+            }
+            mapped.push((mode, lhs, rhs, buffer));
+        }
+        conflicts
+    }
+
+    pub fn summary(&self) -> String {
+        let mut summary = Vec::new();
+        if !self.conditions.is_empty() {
+            summary.push(format!("conditions: {}", self.conditions.iter().join("; ")));
+        }
+        let keymaps: usize = self.keys.iter().map(Keys::mapping_count).sum();
+        if keymaps > 0 {
+            summary.push(format!("{keymaps} keymap(s)"));
+        }
+        let abbreviations: usize = self.abbreviations.iter().map(Abbreviations::count).sum();
+        if abbreviations > 0 {
+            summary.push(format!("{abbreviations} abbreviation(s)"));
+        }
+        if !self.set.is_empty() {
+            summary.push(format!(
+                "sets: {}",
+                self.set
+                    .iter()
+                    .map(|Set(name, ..)| name)
+                    .unique()
+                    .join(", ")
+            ));
+        }
+        let auto_commands = self
+            .auto_commands
+            .iter()
+            .chain(self.augroups.values().flatten());
+        if !self.commands.is_empty() {
+            summary.push(format!(
+                "commands: {}",
+                self.commands.keys().sorted().join(", ")
+            ));
+        }
+        if !(self.w.is_empty() && self.t.is_empty()) {
+            summary.push(format!(
+                "variables: {}",
+                self.w
+                    .keys()
+                    .map(|name| format!("w:{name}"))
+                    .chain(self.t.keys().map(|name| format!("t:{name}")))
+                    .sorted()
+                    .join(", ")
+            ));
+        }
+        if !self.highlight.is_empty() {
+            summary.push(format!("{} highlight group(s)", self.highlight.len()));
+        }
+        if auto_commands.clone().next().is_some() {
+            summary.push(format!("{} autocommand(s)", auto_commands.clone().count()));
+        }
+        let lua = self.keys.iter().filter(|keys| keys.runs_lua()).count()
+            + self
+                .commands
+                .values()
+                .filter(|command| command.runs_lua())
+                .count()
+            + auto_commands
+                .filter(|auto_command| !auto_command.lua.is_empty())
+                .count();
+        if lua > 0 {
+            summary.push(format!("runs arbitrary Lua in {lua} block(s)"));
+        }
+        if !self.vim.is_empty() {
+            summary.push(format!(
+                "runs arbitrary Vimscript in {} block(s)",
+                self.vim.len()
+            ));
+        }
+        for (filetype, section) in self
+            .filetypes
+            .iter()
+            .sorted_by_key(|(filetype, _)| *filetype)
+        {
+            summary.push(format!("filetype {filetype}:"));
+            summary.extend(section.summary().lines().map(|line| format!("  {line}")));
+        }
+        summary.join("\n")
+    }
+
+    /// Options set and lhs mapped by this config and its filetype sections.
+    pub fn defined_names(&self) -> Vec<String> {
+        self.set
+            .iter()
+            .map(|set| set.name().to_owned())
+            .chain(
+                self.keys
+                    .iter()
+                    .flat_map(Keys::mappings)
+                    .map(|(_, lhs, ..)| lhs),
+            )
+            .chain(self.filetypes.values().flat_map(Config::defined_names))
+            .collect()
+    }
+
+    /// Problems that would surface when applying, found without applying anything.
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        for set in self.set.iter().unique_by(|set| set.name()) {
+            if set.enabled().unwrap_or(true) {
+                if let Err(error) = api::get_option_info(set.name()) {
+                    problems.push(format!("Invalid option {}: {error}", set.name()));
                 }
             }
         }
+        problems.extend(self.set.iter().filter_map(Set::invalid_choice));
+        for keys in &self.keys {
+            if keys.lacks_modes() {
+                problems.push("Keys block without `modes` does not map anything".to_owned());
+            }
+            problems.extend(keys.nonrecursive_plugs().map(nonrecursive_plug_warning));
+        }
+        if self
+            .clear_auto_commands
+            .iter()
+            .any(ClearAutoCommands::is_unrestricted)
+        {
+            problems.push(
+                "`clear_auto_commands` entry needs `events`, `patterns` or `group`".to_owned(),
+            );
+        }
+        for abbreviations in &self.abbreviations {
+            for lhs in abbreviations.invalid() {
+                problems.push(format!("Abbreviation `{lhs}` must be a single word"));
+            }
+        }
+        for name in self.commands.keys().sorted() {
+            if let Err(error) = UserCommand::validate_name(name) {
+                problems.push(error);
+            }
+        }
+        for (filetype, section) in self
+            .filetypes
+            .iter()
+            .sorted_by_key(|(filetype, _)| *filetype)
+        {
+            problems.extend(
+                section
+                    .validate()
+                    .into_iter()
+                    .map(|problem| format!("filetype {filetype}: {problem}")),
+            );
+        }
+        problems
+    }
+
+    /// Parses a single config without resolving its `include`s.
+    ///
+    /// ```
+    /// use config::config::{Config, Format};
+    ///
+    /// let config = Config::from_str("[set]\nnumber = true", Format::Toml)?;
+    /// assert_eq!(config.set[0].name(), "number");
+    /// # Ok::<_, String>(())
+    /// ```
+    pub fn from_str(source: &str, format: Format) -> Result<Self, String> {
+        Self::parse(source, format).map_err(|(error, location)| match location {
+            Some((line, column)) => format!("error while parsing {line}:{column}: {error}"),
+            None => format!("error while parsing: {error}"),
+        })
+    }
+
+    /// Deserializes a config from a Lua table with the same shape as a config file.
+    pub fn from_object(object: Object) -> ConvResult<Self> {
+        let mut config = Self::deserialize(oxi::serde::Deserializer::new(object))
+            .map_err(conversion::Error::from)?;
+        config.prepare();
+        Ok(config)
+    }
+
+    /// Resolves the file wide `aliases`, `buffer` and `defaults` in this config and its filetype
+    /// sections.
+    fn prepare(&mut self) {
+        for section in self.filetypes.values_mut() {
+            for (alias, options) in &self.aliases {
+                section
+                    .aliases
+                    .entry(alias.clone())
+                    .or_insert_with(|| options.clone());
+            }
+            if let Some(buffer) = self.buffer {
+                section.buffer.get_or_insert(buffer);
+            }
+            section.defaults.keys.apply_defaults(&self.defaults.keys);
+            section.defaults.set.apply_defaults(&self.defaults.set);
+            section.prepare();
+        }
+        self.expand_aliases();
+        self.propagate_buffer();
+        self.apply_defaults();
+    }
+
+    fn parse(source: &str, format: Format) -> Result<Self, (String, Option<(usize, usize)>)> {
+        let mut config: Self = match format {
+            Format::Yaml => serde_yaml::from_str(source).map_err(|e| {
+                let location = e.location().map(|l| (l.line(), l.column()));
+                (e.to_string(), location)
+            }),
+            // `toml` reports 0-based positions
+            Format::Toml => toml::from_str(source)
+                .map_err(|e| (e.to_string(), e.line_col().map(|(l, c)| (l + 1, c + 1)))),
+        }?;
+        config.prepare();
+        Ok(config)
+    }
+
+    /// Merges `configs` by their conditions, like `load_config` does.
+    ///
+    /// ```
+    /// use config::config::{Condition, Config, Format};
+    ///
+    /// let configs = [
+    ///     "[set]\nnumber = true",
+    ///     "[[conditions]]\nfiletype = \"rust\"\n[set]\ntextwidth = 100",
+    ///     "[set]\nwrap = false",
+    /// ]
+    /// .map(|source| Config::from_str(source, Format::Toml).expect("config is valid"));
+    /// let merged = Config::merge_all(configs);
+    /// assert_eq!(merged.len(), 2);
+    /// assert_eq!(merged[&Condition::default()].set.len(), 2);
+    /// ```
+    pub fn merge_all(configs: impl IntoIterator<Item = Self>) -> HashMap<Condition, Self> {
+        let mut conditional_configs = HashMap::new();
+        for config in configs {
+            config.merge_into_hashmap(&mut conditional_configs);
+        }
+        conditional_configs
+    }
+
+    /// Merges `configs` and applies the unconditional parts, returning the conditional ones.
+    ///
+    /// Applying needs to run inside Neovim, e.g. in an `#[nvim_oxi::test]`:
+    /// ```no_run
+    /// use config::config::{Config, Format};
+    ///
+    /// let config = Config::from_str("[set]\nnumber = true", Format::Toml)?;
+    /// let conditional = Config::apply_all([config])?;
+    /// assert!(conditional.is_empty());
+    /// # Ok::<_, Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn apply_all(
+        configs: impl IntoIterator<Item = Self>,
+    ) -> ApiResult<HashMap<Condition, Self>> {
+        let mut conditional_configs = Self::merge_all(configs);
+        if let Some(config) = conditional_configs.remove(&Condition::default()) {
+            config.apply(false)?;
+        }
+        Ok(conditional_configs)
     }
 
+    /// Loads the config at `path` with all its includes, returning it together with the
+    /// concatenated source of all files involved.
     pub fn load(path: &Path) -> Result<(Self, String), String> {
-        let ext = path
-            .extension()
-            .expect("files matching glob have extension");
-        let file = fs::read_to_string(path)
+        Self::load_including(path, &mut HashSet::new())
+    }
+
+    fn load_including(
+        path: &Path,
+        including: &mut HashSet<PathBuf>,
+    ) -> Result<(Self, String), String> {
+        let canonical = path
+            .canonicalize()
+            .map_err(|error| format!("error while reading {}: {error}", path.display()))?;
+        if !including.insert(canonical.clone()) {
+            return Err(format!("include cycle through {}", path.display()));
+        }
+        let ext = path.extension().unwrap_or_default();
+        let format = Format::from_extension(&ext.to_string_lossy())
+            .map_err(|error| format!("error while parsing {}: {error}", path.display()))?;
+        let mut source = fs::read_to_string(path)
             .map_err(|error| format!("error while reading {}: {error}", path.display()))?;
 
-        Ok((
-            match ext.to_string_lossy().to_ascii_lowercase().as_str() {
-                "json" | "yml" | "yaml" => serde_yaml::from_str(&file).map_err(|e| e.to_string()),
-                "toml" => toml::from_str(&file).map_err(|e| e.to_string()),
-                _ => unreachable!("files matching glob are handled"),
+        let mut config = Self::parse(&source, format)
+            .map_err(|(error, location)| parse_error(path, &source, location, &error))?;
+
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        for include in mem::take(&mut config.include) {
+            let (mut included, included_source) =
+                Self::load_including(&dir.join(include), including)?;
+            if included.is_enabled() {
+                // `merge` is about other config files, an include cannot drop what includes it
+                included.merge = MergeMode::Append;
+                config.merge_from(included);
             }
-            .map_err(|error| format!("error while parsing {}: {error}", path.display()))?,
-            file,
-        ))
+            source.push_str(&included_source);
+        }
+        including.remove(&canonical);
+        Ok((config, source))
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.unwrap_or(true)
+    }
+
+    /// Replaces sets of an alias with one set per option it stands for.
+    fn expand_aliases(&mut self) {
+        let aliases = mem::take(&mut self.aliases);
+        if aliases.is_empty() {
+            return;
+        }
+        self.set = mem::take(&mut self.set)
+            .into_iter()
+            .flat_map(|set| match aliases.get(&set.0) {
+                Some(options) => options
+                    .iter()
+                    .map(|option| Set(option.clone(), set.1, set.2.clone(), set.3.clone()))
+                    .collect(),
+                None => vec![set],
+            })
+            .collect();
+    }
+
+    /// Fills the flags of blocks not setting them from `defaults`.
+    fn apply_defaults(&mut self) {
+        let Defaults { keys, set } = mem::take(&mut self.defaults);
+        for block in &mut self.keys {
+            block.apply_defaults(&keys);
+        }
+        for Set(.., modifiers) in &mut self.set {
+            modifiers.apply_defaults(&set);
+        }
+    }
+
+    /// Moves the config wide `buffer` to the items not setting it, so it survives merging.
+    fn propagate_buffer(&mut self) {
+        if let Some(buffer) = self.buffer.take() {
+            for keys in &mut self.keys {
+                keys.default_buffer(buffer);
+            }
+            for abbreviations in &mut self.abbreviations {
+                abbreviations.default_buffer(buffer);
+            }
+            for Set(.., modifiers) in &mut self.set {
+                modifiers.buffer.get_or_insert(BufferTarget::Local(buffer));
+            }
+            for command in self.commands.values_mut() {
+                command.default_buffer(buffer);
+            }
+        }
+    }
+
+    pub fn apply_highlights(&self) -> ApiResult {
+        for (name, highlight) in &self.highlight {
+            highlight.apply(name)?;
+        }
+        Ok(())
     }
 
     pub fn apply(&self, buffer: bool) -> ApiResult {
+        for section in Section::ALL {
+            self.apply_section(section, buffer)?;
+        }
+        Ok(())
+    }
+
+    pub fn apply_section(&self, section: Section, buffer: bool) -> ApiResult {
+        match section {
+            Section::Keys => self.apply_keys(buffer),
+            Section::Abbreviations => self.apply_abbreviations(buffer),
+            Section::Set => Set::apply_all(&self.set, buffer),
+            Section::Vim => self.apply_vim(),
+            Section::Highlight => self.apply_highlights(),
+            Section::Variables => {
+                apply_window_vars(&self.w)?;
+                apply_tab_vars(&self.t)
+            }
+            Section::Commands => self.apply_commands(buffer),
+            Section::AutoCommands => self.apply_auto_commands(),
+        }
+    }
+
+    /// Runs each script of `vim`, a script can span multiple lines, e.g. to define a function.
+    pub fn apply_vim(&self) -> ApiResult {
+        for script in &self.vim {
+            api::call_function::<_, String>("execute", (Array::from_iter(script.lines()),))?;
+        }
+        Ok(())
+    }
+
+    pub fn apply_keys(&self, buffer: bool) -> ApiResult {
         for key in &self.keys {
             key.apply(buffer)?;
         }
-        for set in &self.set {
-            set.apply(buffer)?;
+        Ok(())
+    }
+
+    pub fn apply_abbreviations(&self, buffer: bool) -> ApiResult {
+        for abbreviations in &self.abbreviations {
+            abbreviations.apply(buffer)?;
         }
-        for AutoCommand {
-            triggers,
-            cmd,
-            lua,
-            pattern,
-        } in &self.auto_commands
-        {
-            for cmd in cmd.clone().into_iter().chain(
-                lua.iter()
-                    .map(|lua| format!("lua {lua}{}", if lua.ends_with(')') { "" } else { "()" })),
-            ) {
-                create_autocmd(
-                    triggers.iter().map(AsRef::as_ref),
-                    &CreateAutocmdOpts::builder()
-                        .patterns(pattern.iter().map(AsRef::as_ref))
-                        .command(cmd.as_str())
-                        .build(),
-                )?;
+        Ok(())
+    }
+
+    pub fn apply_commands(&self, buffer: bool) -> ApiResult {
+        for (name, command) in &self.commands {
+            command.apply(name, buffer)?;
+        }
+        Ok(())
+    }
+
+    pub fn apply_auto_commands(&self) -> ApiResult {
+        for clear in &self.clear_auto_commands {
+            clear.apply()?;
+        }
+        for auto_command in &self.auto_commands {
+            auto_command.apply(None)?;
+        }
+        for (group, auto_commands) in &self.augroups {
+            let group =
+                api::create_augroup(group, &CreateAugroupOpts::builder().clear(true).build())?;
+            for auto_command in auto_commands {
+                auto_command.apply(Some(group))?;
             }
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sections_inherit_file_wide_settings() {
+        let config = Config::from_str(
+            r#"
+            buffer = true
+            [aliases]
+            indent = ["shiftwidth", "tabstop"]
+            [defaults.set]
+            dedupe = true
+            [filetype.rust.set]
+            indent = 4
+            "#,
+            Format::Toml,
+        )
+        .expect("config is valid");
+        let section = &config.filetypes["rust"];
+        assert_eq!(
+            section.set.iter().map(Set::name).collect_vec(),
+            ["shiftwidth", "tabstop"]
+        );
+        for Set(.., modifiers) in &section.set {
+            assert_eq!(modifiers.buffer, Some(BufferTarget::Local(true)));
+            assert_eq!(modifiers.dedupe, Some(true));
+        }
+    }
+
+    #[test]
+    fn conflicts_cover_filetype_sections() {
+        let config = |source| Config::from_str(source, Format::Toml).expect("config is valid");
+        let section = config("[filetype.rust.set]\ntextwidth = 100");
+        let dark = config("[[conditions]]\nbackground = \"dark\"\n[set]\ntextwidth = 80");
+        let rust = config("[[conditions]]\nfiletype = [\"rust\", \"toml\"]\n[set]\ntextwidth = 80");
+        let lua = config("[[conditions]]\nfiletype = \"lua\"\n[set]\ntextwidth = 80");
+        assert_eq!(
+            section.conflicts(&dark),
+            [
+                "`textwidth` is assigned Integer(100) and Integer(80) under overlapping \
+                 conditions: (filetype = rust) and (background = dark)"
+            ]
+        );
+        // merged under the same condition, in the order the configs are loaded
+        assert!(section.conflicts(&rust).is_empty());
+        assert!(section.conflicts(&lua).is_empty());
+        assert!(rust.conflicts(&lua).is_empty());
+    }
+
+    #[test]
+    fn includes_cannot_replace_the_including_config() {
+        let dir = std::env::temp_dir().join(format!("config-nvim-include-{}", std::process::id()));
+        fs::create_dir_all(&dir).expect("test dir is created");
+        fs::write(
+            dir.join("main.toml"),
+            "include = \"included.toml\"\n[set]\nnumber = true",
+        )
+        .expect("config is written");
+        fs::write(
+            dir.join("included.toml"),
+            "merge = \"replace\"\n[set]\nwrap = false",
+        )
+        .expect("config is written");
+        let (config, _) = Config::load(&dir.join("main.toml")).expect("config is valid");
+        fs::remove_dir_all(dir).expect("test dir is removed");
+        assert_eq!(
+            config.set.iter().map(Set::name).collect_vec(),
+            ["number", "wrap"]
+        );
+        assert_eq!(config.merge, MergeMode::Append);
+    }
+
+    #[test]
+    fn disabled_configs_contribute_nothing() {
+        let dir = std::env::temp_dir().join(format!("config-nvim-enabled-{}", std::process::id()));
+        fs::create_dir_all(&dir).expect("test dir is created");
+        fs::write(
+            dir.join("main.toml"),
+            "include = [\"enabled.toml\", \"disabled.toml\"]\n[set]\nnumber = true",
+        )
+        .expect("config is written");
+        fs::write(
+            dir.join("enabled.toml"),
+            "enabled = true\n[set]\nwrap = false",
+        )
+        .expect("config is written");
+        fs::write(
+            dir.join("disabled.toml"),
+            "enabled = false\n[set]\nlist = true",
+        )
+        .expect("config is written");
+        let (config, _) = Config::load(&dir.join("main.toml")).expect("config is valid");
+        let (disabled, _) = Config::load(&dir.join("disabled.toml")).expect("config is valid");
+        fs::remove_dir_all(dir).expect("test dir is removed");
+        assert!(config.is_enabled());
+        assert!(!disabled.is_enabled());
+        assert_eq!(
+            config.set.iter().map(Set::name).collect_vec(),
+            ["number", "wrap"]
+        );
+    }
+
+    #[test]
+    fn replacing_configs_drop_earlier_sets_and_keys() {
+        let config = |source| Config::from_str(source, Format::Toml).expect("config is valid");
+        let user = || config("[set]\nnumber = true\n[keys]\nmodes = \"n\"\nQ = \"gq\"");
+        let names = |config: &Config| config.set.iter().map(Set::name).collect_vec();
+
+        let mut appended = user();
+        appended.merge_from(config("[set]\nwrap = false"));
+        assert_eq!(names(&appended), ["number", "wrap"]);
+        assert_eq!(appended.keys.len(), 1);
+
+        let mut replaced = user();
+        replaced.merge_from(config("merge = \"replace\"\n[set]\nwrap = false"));
+        assert_eq!(names(&replaced), ["wrap"]);
+        assert!(replaced.keys.is_empty());
+    }
+
+    #[test]
+    fn map_entries_are_merged_across_configs() {
+        let config = |source| Config::from_str(source, Format::Toml).expect("config is valid");
+        let listchars = |config: &Config| match config.set.last() {
+            Some(Set(_, Operation::Assign, SetValue::Map(map), _)) => map.clone(),
+            set => panic!("expected listchars to be assigned a map, got {set:?}"),
+        };
+        let mut merged = config("[set.listchars]\ntab = \"> \"");
+        merged.merge_from(config("[set.listchars]\ntrail = \"-\""));
+        assert_eq!(
+            listchars(&merged),
+            HashMap::from([
+                ("tab".to_owned(), "> ".to_owned()),
+                ("trail".to_owned(), "-".to_owned())
+            ])
+        );
+
+        merged.merge_from(config("[set.listchars]\nassign = { eol = \"$\" }"));
+        assert_eq!(
+            listchars(&merged),
+            HashMap::from([("eol".to_owned(), "$".to_owned())])
+        );
+    }
+
+    #[nvim_oxi::test]
+    fn vim_scripts_are_executed() {
+        let config = Config::from_str(
+            r#"
+            vim = [
+                "let g:config_nvim_foo = 1",
+                """
+                function! ConfigNvimTwo()
+                    return 2
+                endfunction
+                """,
+            ]
+            "#,
+            Format::Toml,
+        )
+        .expect("config is valid");
+        config.apply_vim().expect("scripts are executed");
+        let foo: i64 = api::get_var("config_nvim_foo").expect("variable is set");
+        let two: i64 = api::call_function("ConfigNvimTwo", ()).expect("function is defined");
+        assert_eq!((foo, two), (1, 2));
+    }
+
+    #[nvim_oxi::test]
+    fn augroups_are_cleared_when_applied() {
+        let group = api::create_augroup("ConfigNvimGroup", &CreateAugroupOpts::default())
+            .expect("group is created");
+        api::create_autocmd(
+            ["BufEnter"],
+            &CreateAutocmdOpts::builder()
+                .group(group)
+                .command("echo")
+                .build(),
+        )
+        .expect("stale autocommand is created");
+        let config = Config::from_str(
+            r#"
+            [[augroups.ConfigNvimGroup]]
+            triggers = "BufWritePre"
+            cmd = "echo"
+            [[augroups.ConfigNvimGroup]]
+            triggers = "BufReadPost"
+            cmd = "echo"
+            "#,
+            Format::Toml,
+        )
+        .expect("config is valid");
+        for _ in 0..2 {
+            config
+                .apply_auto_commands()
+                .expect("autocommands are created");
+        }
+        let events =
+            api::get_autocmds(&GetAutocmdsOpts::builder().group("ConfigNvimGroup").build())
+                .expect("autocommands are listed")
+                .map(|autocmd| autocmd.event)
+                .sorted()
+                .collect_vec();
+        assert_eq!(events, ["BufReadPost", "BufWritePre"]);
+    }
+
+    #[nvim_oxi::test]
+    fn auto_commands_are_cleared_by_group() {
+        let group = api::create_augroup("ConfigNvimClear", &CreateAugroupOpts::default())
+            .expect("group is created");
+        for group in [Some(group), None] {
+            let mut opts = CreateAutocmdOpts::builder();
+            opts.patterns(["ConfigNvimClear"]).command("echo");
+            if let Some(group) = group {
+                opts.group(group);
+            }
+            api::create_autocmd(["User"], &opts.build()).expect("autocommand is created");
+        }
+        let groups = || {
+            api::get_autocmds(
+                &GetAutocmdsOpts::builder()
+                    .events(["User"])
+                    .patterns(["ConfigNvimClear"])
+                    .build(),
+            )
+            .expect("autocommands are listed")
+            .map(|autocmd| autocmd.group)
+            .collect_vec()
+        };
+        assert_eq!(groups().len(), 2);
+
+        let config = |source| Config::from_str(source, Format::Toml).expect("config is valid");
+        config("[[clear_auto_commands]]")
+            .apply_auto_commands()
+            .expect("unrestricted clear is refused");
+        assert_eq!(groups().len(), 2);
+        config("[[clear_auto_commands]]\ngroup = \"ConfigNvimClear\"")
+            .apply_auto_commands()
+            .expect("group is cleared");
+        assert_eq!(groups(), [None]);
+    }
+
+    #[nvim_oxi::test]
+    fn sections_are_applied_on_their_own() {
+        let config = Config::from_str(
+            "[set]\ntextwidth = 80\n[keys]\nmodes = \"n\"\ngX = \"<Cmd>echo<CR>\"",
+            Format::Toml,
+        )
+        .expect("config is valid");
+        let textwidth = || -> i64 {
+            api::get_option_value("textwidth", &OptionValueOpts::default())
+                .expect("textwidth is readable")
+        };
+        let mapped =
+            || api::get_keymap(oxi::api::types::Mode::Normal).any(|mapping| mapping.lhs == "gX");
+        let keys = Section::from_name("keys").expect("keys is a section");
+        config.apply_section(keys, false).expect("keys are applied");
+        assert!(mapped());
+        assert_eq!(textwidth(), 0);
+        config
+            .apply_section(Section::Set, false)
+            .expect("options are set");
+        assert_eq!(textwidth(), 80);
+        assert!(Section::from_name("options").is_none());
+    }
+}
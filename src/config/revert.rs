@@ -0,0 +1,209 @@
+use std::{
+    cell::{Cell, RefCell},
+    mem,
+};
+
+use oxi::api::types::Mode;
+
+use crate::*;
+
+thread_local! {
+    /// Changes of the configs currently being applied, innermost last as applying can trigger
+    /// autocommands applying other configs.
+    static RECORDING: RefCell<Vec<Revert>> = RefCell::default();
+}
+
+/// Global changes made by a conditional config, undone once its condition stops holding.
+#[derive(Debug, Default)]
+pub struct Revert {
+    /// Options with their value from before the config first changed them.
+    options: Vec<SnapshotEntry>,
+    /// Global keymaps created by the config.
+    keymaps: Vec<(Mode, String)>,
+}
+
+/// Records the previous value of an option changed by the config currently being applied.
+pub fn record_option(entry: &SnapshotEntry) {
+    RECORDING.with(|recording| {
+        if let Some(revert) = recording.borrow_mut().last_mut() {
+            revert.add_option(entry.clone());
+        }
+    });
+}
+
+/// Records a global keymap created by the config currently being applied.
+pub fn record_keymap(mode: Mode, lhs: &str) {
+    RECORDING.with(|recording| {
+        if let Some(revert) = recording.borrow_mut().last_mut() {
+            revert.add_keymap(mode, lhs.to_owned());
+        }
+    });
+}
+
+impl Revert {
+    /// Runs `apply`, returning its result along with the changes it made.
+    pub fn recording(apply: impl FnOnce() -> ApiResult) -> (ApiResult, Self) {
+        RECORDING.with(|recording| recording.borrow_mut().push(Self::default()));
+        let result = apply();
+        let revert = RECORDING
+            .with(|recording| recording.borrow_mut().pop())
+            .unwrap_or_default();
+        (result, revert)
+    }
+
+    fn add_option(&mut self, entry: SnapshotEntry) {
+        let (name, _, buffer, hint, _) = &entry;
+        if !self
+            .options
+            .iter()
+            .any(|(recorded, _, recorded_buffer, recorded_hint, _)| {
+                recorded == name && recorded_buffer == buffer && recorded_hint == hint
+            })
+        {
+            self.options.push(entry);
+        }
+    }
+
+    fn add_keymap(&mut self, mode: Mode, lhs: String) {
+        if !self.keymaps.contains(&(mode, lhs.clone())) {
+            self.keymaps.push((mode, lhs));
+        }
+    }
+
+    /// Adds the changes of `other`, keeping the older previous values.
+    pub fn extend(&mut self, other: Self) {
+        for entry in other.options {
+            self.add_option(entry);
+        }
+        for (mode, lhs) in other.keymaps {
+            self.add_keymap(mode, lhs);
+        }
+    }
+
+    /// Deletes the recorded keymaps and restores the recorded options.
+    pub fn restore(self) -> Result<()> {
+        for (mode, lhs) in self.keymaps {
+            if let Err(error) = api::del_keymap(mode, &lhs) {
+                log_error!("Error while removing `{lhs}` in mode {mode:?}: \n{error}");
+            }
+        }
+        restore_options(self.options)
+    }
+}
+
+/// A conditional config that can revert its global changes, if `revert_conditional` is set.
+pub struct RevertingConfig {
+    config: Config,
+    revert: Option<RefCell<Revert>>,
+    /// Whether the sections applied once per load were applied already.
+    applied: Cell<bool>,
+}
+
+impl RevertingConfig {
+    pub fn new(config: Config, revert: bool) -> Self {
+        Self {
+            config,
+            revert: revert.then(RefCell::default),
+            applied: Cell::default(),
+        }
+    }
+
+    /// Applies the config, its `vim` scripts and autocommands only the first time.
+    pub fn apply(&self, buffer: bool) -> ApiResult {
+        let first = !self.applied.replace(true);
+        let apply = || {
+            for section in Section::ALL {
+                if first || !section.is_once_per_load() {
+                    self.config.apply_section(section, buffer)?;
+                }
+            }
+            Ok(())
+        };
+        let Some(revert) = &self.revert else {
+            return apply();
+        };
+        let (result, recorded) = Revert::recording(apply);
+        revert.borrow_mut().extend(recorded);
+        result
+    }
+
+    /// Reverts the changes made since the last restore.
+    pub fn restore(&self) -> Result<()> {
+        let Some(revert) = &self.revert else {
+            return Ok(());
+        };
+        // not borrowed while restoring, which can trigger autocommands applying this config
+        let revert = mem::take(&mut *revert.borrow_mut());
+        revert.restore()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+
+    #[nvim_oxi::test]
+    fn autocommands_are_created_once_per_load() {
+        let config = Config::from_str(
+            r#"
+            vim = "let g:config_nvim_applied = get(g:, 'config_nvim_applied', 0) + 1"
+            [[auto_commands]]
+            triggers = "User"
+            pattern = "ConfigNvimOnce"
+            cmd = "echo"
+            "#,
+            Format::Toml,
+        )
+        .expect("config is valid");
+        let config = Rc::new(RevertingConfig::new(config, false));
+        for _ in 0..2 {
+            let buffer = api::create_buf(true, false).expect("buffer is created");
+            let config = config.clone();
+            buffer
+                .call(move |()| config.apply(true))
+                .expect("config is applied");
+        }
+        let autocmds = api::get_autocmds(
+            &GetAutocmdsOpts::builder()
+                .events(["User"])
+                .patterns(["ConfigNvimOnce"])
+                .build(),
+        )
+        .expect("autocommands are listed")
+        .count();
+        let applied: i64 = api::get_var("config_nvim_applied").expect("vim script ran");
+        assert_eq!((autocmds, applied), (1, 1));
+    }
+
+    #[nvim_oxi::test]
+    fn restore_reverts_options_and_keymaps() {
+        let config = Config::from_str(
+            r#"
+            [set]
+            laststatus = 0
+            [[keys]]
+            modes = "n"
+            gX = "<Cmd>echo<CR>"
+            "#,
+            Format::Toml,
+        )
+        .expect("config is valid");
+        let laststatus = || -> i64 {
+            api::get_option_value("laststatus", &OptionValueOpts::default())
+                .expect("laststatus is readable")
+        };
+        let mapped = || api::get_keymap(Mode::Normal).any(|mapping| mapping.lhs == "gX");
+        let before = laststatus();
+        let config = RevertingConfig::new(config, true);
+        for _ in 0..2 {
+            config.apply(false).expect("config is applied");
+            assert_eq!(laststatus(), 0);
+            assert!(mapped());
+            config.restore().expect("config is restored");
+            assert_eq!(laststatus(), before);
+            assert!(!mapped());
+        }
+    }
+}
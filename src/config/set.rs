@@ -1,20 +1,196 @@
-use std::collections::{HashMap, HashSet};
+use std::{
+    cell::{Cell, RefCell},
+    collections::{HashMap, HashSet},
+    mem,
+    rc::Rc,
+};
 
 use derive_more::Display;
 use oxi::{
     api::{
         types::{self, OptionInfos},
-        Buffer, Window,
+        Buffer,
     },
     conversion::{self, ToObject},
     Object, ObjectKind,
 };
-use serde::{Deserialize, Serialize};
+use serde::{
+    de::{IgnoredAny, IntoDeserializer},
+    Deserialize, Deserializer, Serialize,
+};
 
 use crate::*;
 
+pub type SnapshotEntry = (String, types::OptionScope, bool, Option<SetScope>, SetValue);
+
+thread_local! {
+    /// Option values from before they were first changed by the most recent apply.
+    static SNAPSHOT: RefCell<Vec<SnapshotEntry>> = RefCell::default();
+    /// Whether options changed now are part of the snapshot.
+    static SNAPSHOT_OPEN: Cell<bool> = Cell::default();
+}
+
+/// Runs `f` as a new snapshot for [`undo_snapshot`], discarding the previous one.
+///
+/// Only options changed by `f` are recorded, not the ones changed later by conditional configs.
+pub fn snapshot<R>(f: impl FnOnce() -> R) -> R {
+    SNAPSHOT.with(|snapshot| snapshot.borrow_mut().clear());
+    SNAPSHOT_OPEN.with(|open| open.set(true));
+    let result = f();
+    SNAPSHOT_OPEN.with(|open| open.set(false));
+    result
+}
+
+/// Restores the options changed in the last [`snapshot`], returning how many were restored.
+pub fn undo_snapshot() -> Result<usize> {
+    let snapshot = SNAPSHOT.with(|snapshot| mem::take(&mut *snapshot.borrow_mut()));
+    let restored = snapshot.len();
+    restore_options(snapshot)?;
+    Ok(restored)
+}
+
+/// Sets options back to the recorded values, the most recently recorded first.
+pub fn restore_options(entries: Vec<SnapshotEntry>) -> Result<()> {
+    for (name, scope, buffer, hint, value) in entries.into_iter().rev() {
+        set_option(scope, buffer, hint)?(&name, value.clone()).or_else(|err| {
+            log_error!("Error while restoring {value:?} to {name}: \n{err}");
+            Ok::<_, Error>(())
+        })?;
+    }
+    Ok(())
+}
+
+fn record_snapshot(
+    name: &str,
+    scope: types::OptionScope,
+    buffer: bool,
+    hint: Option<SetScope>,
+    value: &SetValue,
+) {
+    // buffer options stay with the buffer they were set for
+    if buffer && matches!(scope, types::OptionScope::Buffer) {
+        return;
+    }
+    record_option(&(name.to_owned(), scope, buffer, hint, value.clone()));
+    if !SNAPSHOT_OPEN.with(Cell::get) {
+        return;
+    }
+    SNAPSHOT.with(|snapshot| {
+        let mut snapshot = snapshot.borrow_mut();
+        if !snapshot
+            .iter()
+            .any(|(recorded, _, recorded_buffer, recorded_hint, _)| {
+                recorded == name && *recorded_buffer == buffer && *recorded_hint == hint
+            })
+        {
+            snapshot.push((name.to_owned(), scope, buffer, hint, value.clone()));
+        }
+    });
+}
+
+// TODO https://github.com/neovim/neovim/issues/19574
+// Hardcode for now from: https://github.com/neovim/neovim/blob/e59bc078de624a5f3220bfd2713be3f8978c5672/runtime/lua/vim/_meta.lua#L199-L203
+/// Commalist options whose entries are `key:value` pairs, extended by `map_options` from `setup`.
+pub const MAP_OPTIONS: &[&str] = &["fillchars", "listchars", "winhl"];
+
+fn is_map_option(name: &str) -> bool {
+    MAP_OPTIONS.contains(&name) || Setup::get().map_options.iter().any(|option| option == name)
+}
+
+/// Values of string options accepting a fixed set of them, a trailing `:` allows a number after
+/// it, e.g. `yes:2`.
+const OPTION_CHOICES: &[(&str, &[&str])] = &[
+    ("background", &["dark", "light"]),
+    ("bufhidden", &["", "hide", "unload", "delete", "wipe"]),
+    (
+        "buftype",
+        &[
+            "", "acwrite", "help", "nofile", "nowrite", "quickfix", "terminal", "prompt",
+        ],
+    ),
+    ("fileformat", &["unix", "dos", "mac"]),
+    (
+        "foldmethod",
+        &["manual", "indent", "expr", "marker", "syntax", "diff"],
+    ),
+    ("inccommand", &["", "nosplit", "split"]),
+    ("mousemodel", &["extend", "popup", "popup_setpos"]),
+    ("selection", &["old", "inclusive", "exclusive"]),
+    (
+        "signcolumn",
+        &["auto", "auto:", "no", "yes", "yes:", "number"],
+    ),
+    ("splitkeep", &["cursor", "screen", "topline"]),
+];
+
+/// Error for assigning `value` to option `name` (written as `key`), if it is not one of the
+/// known choices.
+fn invalid_choice(key: &str, name: &str, value: &str) -> Option<String> {
+    let (_, choices) = OPTION_CHOICES.iter().find(|(option, _)| *option == name)?;
+    let valid = choices.iter().any(|choice| {
+        if choice.ends_with(':') {
+            value
+                .strip_prefix(choice)
+                .is_some_and(|number| number.parse::<u8>().is_ok())
+        } else {
+            value == *choice
+        }
+    });
+    (!valid).then(|| {
+        let expected = choices
+            .iter()
+            .map(|choice| match *choice {
+                "" => "\"\"".to_owned(),
+                choice if choice.ends_with(':') => format!("{choice}N"),
+                choice => choice.to_owned(),
+            })
+            .join("|");
+        format!("Invalid value `{value}` for {key}, expected {expected}")
+    })
+}
+
 #[derive(Debug, Deserialize, Clone)]
-pub struct Set(pub String, pub Operation, pub SetValue);
+pub struct Set(pub String, pub Operation, pub SetValue, pub SetModifiers);
+
+#[derive(Debug, Deserialize, Default, Clone)]
+#[serde(default)]
+pub struct SetModifiers {
+    /// Only set the option when `has()` reports this feature.
+    pub if_has: Option<String>,
+    /// Overrides whether the option is set buffer-locally, or the buffer it is set for.
+    pub buffer: Option<BufferTarget>,
+    /// Skip entries already present when appending or prepending to a list option, defaults to
+    /// `dedupe_lists` from `setup`.
+    pub dedupe: Option<bool>,
+    /// Sets the local or global value like `:setlocal`/`:setglobal`, takes precedence over
+    /// `buffer`.
+    pub scope: Option<SetScope>,
+    /// Position in a list option to `insert` at, clamped to its end.
+    pub index: Option<i64>,
+    /// Whether a map was assigned by listing its entries, which are then merged with the map
+    /// assigned by earlier configs.
+    #[serde(skip)]
+    pub merge_map: bool,
+}
+
+impl SetModifiers {
+    /// Fills the modifiers not set from `defaults`.
+    pub fn apply_defaults(&mut self, defaults: &Self) {
+        if self.if_has.is_none() {
+            self.if_has = defaults.if_has.clone();
+        }
+        self.buffer = self.buffer.or(defaults.buffer);
+        self.dedupe = self.dedupe.or(defaults.dedupe);
+        self.scope = self.scope.or(defaults.scope);
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum SetScope {
+    Local,
+    Global,
+}
 
 #[derive(Debug, Deserialize)]
 #[serde(untagged)]
@@ -23,25 +199,17 @@ pub enum SetDeserializer {
     Assignment(HashMap<String, ValueOrOp>),
 }
 
-impl From<SetDeserializer> for Vec<Set> {
-    fn from(d: SetDeserializer) -> Self {
+impl TryFrom<SetDeserializer> for Vec<Set> {
+    type Error = String;
+
+    fn try_from(d: SetDeserializer) -> Result<Self, Self::Error> {
         match d {
-            SetDeserializer::Flag(name) => {
-                vec![if let Some(name) = name.strip_prefix("no") {
-                    Set(name.to_string(), Operation::Assign, SetValue::Bool(false))
-                } else {
-                    Set(name.to_string(), Operation::Assign, SetValue::Bool(true))
-                }]
-            }
+            SetDeserializer::Flag(name) => Ok(vec![Set::flag(&name)]),
             SetDeserializer::Assignment(map) => map
                 .into_iter()
-                .flat_map(|(name, value)| match value {
-                    ValueOrOp::Operation(map) => map
-                        .into_iter()
-                        .map(|(operation, value)| Set(name.clone(), operation, value))
-                        .collect(),
-                    ValueOrOp::Value(value) => vec![Set(name, Operation::Assign, value)],
-                })
+                .filter(|(name, _)| !is_comment(name))
+                .map(|(name, value)| value.into_sets(name))
+                .flatten_ok()
                 .collect(),
         }
     }
@@ -59,36 +227,180 @@ pub enum SetsDeserializer {
     },
 }
 
-impl From<SetsDeserializer> for Vec<Set> {
-    fn from(d: SetsDeserializer) -> Self {
+impl TryFrom<SetsDeserializer> for Vec<Set> {
+    type Error = String;
+
+    fn try_from(d: SetsDeserializer) -> Result<Self, Self::Error> {
         match d {
-            SetsDeserializer::List(list) => list.into_iter().flat_map(Vec::from).collect(),
+            SetsDeserializer::List(list) => {
+                list.into_iter().map(Vec::try_from).flatten_ok().collect()
+            }
             SetsDeserializer::Map { flags, map } => flags
                 .into_iter()
-                .flat_map(Vec::from)
-                .chain(map.into_iter().flat_map(|(name, value)| {
-                    match value {
-                        ValueOrOp::Operation(map) => map
-                            .into_iter()
-                            .map(|(operation, value)| Set(name.clone(), operation, value))
-                            .collect(),
-                        ValueOrOp::Value(value) => vec![Set(name, Operation::Assign, value)],
-                    }
-                }))
+                .map(Vec::try_from)
+                .chain(
+                    map.into_iter()
+                        .filter(|(name, _)| !is_comment(name))
+                        .map(|(name, value)| value.into_sets(name)),
+                )
+                .flatten_ok()
                 .collect(),
         }
     }
 }
 
+/// Keys of the table form of a set, besides map entries.
+const SET_KEYS: &[&str] = &[
+    "append", "prepend", "remove", "assign", "value", "toggle", "insert", "if_has", "buffer",
+    "dedupe", "scope", "index",
+];
+
+/// Whether `key` annotates a table instead of being part of it, i.e. starts with `_` or is
+/// `description`, as JSON has no comments.
+pub fn is_comment(key: &str) -> bool {
+    key.starts_with('_') || key == "description"
+}
+
+/// Levenshtein distance between `a` and `b`.
+fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, a) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, b) in b.iter().enumerate() {
+            let above = row[j + 1];
+            row[j + 1] = (diagonal + usize::from(a != *b))
+                .min(row[j] + 1)
+                .min(above + 1);
+            diagonal = above;
+        }
+    }
+    row[b.len()]
+}
+
+/// The operation or modifier `key` is most likely a misspelling of.
+fn suggest_set_key(key: &str) -> Option<&'static str> {
+    SET_KEYS
+        .iter()
+        .copied()
+        .map(|known| (distance(key, known), known))
+        .filter(|&(distance, _)| distance > 0 && distance <= 2)
+        .min()
+        .map(|(_, known)| known)
+}
+
+fn unknown_set_key(name: &str, key: &str) -> String {
+    match suggest_set_key(key) {
+        Some(suggestion) => {
+            format!("unknown operation `{key}` for {name}, did you mean `{suggestion}`?")
+        }
+        None => format!(
+            "unknown operation `{key}` for {name}, expected one of {}",
+            SET_KEYS.join(", ")
+        ),
+    }
+}
+
 #[derive(Debug, Deserialize)]
 #[serde(untagged)]
 #[serde(deny_unknown_fields)]
 pub enum ValueOrOp {
-    Operation(HashMap<Operation, SetValue>),
+    Operation(Operations),
     Value(SetValue),
+    /// A table that is neither, kept to report its unknown keys.
+    Invalid(HashMap<String, IgnoredAny>),
+}
+
+impl ValueOrOp {
+    fn into_sets(self, name: String) -> Result<Vec<Set>, String> {
+        Ok(match self {
+            ValueOrOp::Operation(Operations {
+                modifiers,
+                operations,
+            }) => operations
+                .into_iter()
+                .filter_map(|(key, value)| match key {
+                    OperationKey::Operation(operation) => Some((operation, value)),
+                    OperationKey::Comment => None,
+                })
+                .map(|(operation, value)| match (operation, value) {
+                    (_, OperationValue::Value(value)) => {
+                        Ok(Set(name.clone(), operation, value, modifiers.clone()))
+                    }
+                    (Operation::Insert, OperationValue::Indexed { index, value }) => {
+                        let modifiers = SetModifiers {
+                            index: Some(index),
+                            ..modifiers.clone()
+                        };
+                        Ok(Set(name.clone(), operation, value, modifiers))
+                    }
+                    (_, OperationValue::Indexed { .. }) => Err(format!(
+                        "{name} takes an `index` and `value` table only for `insert`, not for \
+                         {operation}"
+                    )),
+                })
+                .collect::<Result<_, _>>()?,
+            ValueOrOp::Value(value) => {
+                let modifiers = SetModifiers {
+                    merge_map: matches!(value, SetValue::Map(_)),
+                    ..SetModifiers::default()
+                };
+                vec![Set(name, Operation::Assign, value, modifiers)]
+            }
+            ValueOrOp::Invalid(map) => {
+                let key = map
+                    .keys()
+                    .filter(|key| !is_comment(key))
+                    .find(|key| !SET_KEYS.contains(&key.as_str()))
+                    .or_else(|| map.keys().next())
+                    .map_or("", String::as_str);
+                return Err(unknown_set_key(&name, key));
+            }
+        })
+    }
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Debug, Deserialize)]
+pub struct Operations {
+    #[serde(flatten)]
+    modifiers: SetModifiers,
+    #[serde(flatten)]
+    operations: HashMap<OperationKey, OperationValue>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum OperationValue {
+    /// `insert = { index = 1, value = ... }`, the same as `insert` with the `index` modifier.
+    Indexed {
+        index: i64,
+        value: SetValue,
+    },
+    Value(SetValue),
+}
+
+#[derive(Debug, PartialEq, Eq, Hash)]
+enum OperationKey {
+    Operation(Operation),
+    /// Ignored, see [`is_comment`].
+    Comment,
+}
+
+impl<'de> Deserialize<'de> for OperationKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let key = String::deserialize(deserializer)?;
+        if is_comment(&key) {
+            return Ok(Self::Comment);
+        }
+        Operation::deserialize(IntoDeserializer::<D::Error>::into_deserializer(
+            key.as_str(),
+        ))
+        .map(Self::Operation)
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 #[serde(untagged)]
 pub enum SetValue {
     Bool(bool),
@@ -114,6 +426,14 @@ pub enum Operation {
     #[serde(alias = "value", alias = "=", alias = "assign")]
     #[display(fmt = "assigning")]
     Assign,
+    /// Inserts into a list option at the `index` modifier.
+    #[serde(alias = "insert")]
+    #[display(fmt = "inserting")]
+    Insert,
+    /// Negates a boolean option, the value is ignored.
+    #[serde(alias = "~", alias = "toggle")]
+    #[display(fmt = "toggling")]
+    Toggle,
 }
 
 impl SetValue {
@@ -128,9 +448,7 @@ impl SetValue {
         if commalist {
             let s = String::deserialize(deserializer)?;
             let s: Vec<_> = s.split(',').map(String::from).collect();
-            // TODO https://github.com/neovim/neovim/issues/19574
-            // Hardcode for now from: https://github.com/neovim/neovim/blob/e59bc078de624a5f3220bfd2713be3f8978c5672/runtime/lua/vim/_meta.lua#L199-L203
-            if matches!(name, "fillchars" | "listchars" | "winhl") {
+            if is_map_option(name) {
                 Ok(Self::Map(
                         s.into_iter()
                             .filter(|s|!s.is_empty())
@@ -158,8 +476,9 @@ impl SetValue {
                 ObjectKind::Boolean => Ok(Self::Bool(Deserialize::deserialize(deserializer)?)),
                 ObjectKind::Float => Ok(Self::Float(Deserialize::deserialize(deserializer)?)),
                 ObjectKind::Integer => Ok(Self::Integer(Deserialize::deserialize(deserializer)?)),
+                ObjectKind::String => Ok(Self::String(Deserialize::deserialize(deserializer)?)),
                 kind => Err(conversion::Error::FromWrongType {
-                    expected: "string, boolean or float",
+                    expected: "string, boolean, integer or float",
                     actual: kind.as_static(),
                 }),
             }
@@ -167,6 +486,136 @@ impl SetValue {
     }
 }
 
+/// Expands a leading `~` and `$VAR`/`${VAR}`, with `$$` for a literal `$`.
+///
+/// Undefined variables are left untouched, so values like `grepprg = "rg $*"` keep working.
+pub fn expand_env(value: &str) -> String {
+    let mut expanded = String::with_capacity(value.len());
+    let mut rest = value;
+    if let Some(after) = rest.strip_prefix('~') {
+        if after.is_empty() || after.starts_with(['/', '\\']) {
+            if let Some(home) = home::home_dir() {
+                expanded.push_str(&home.to_string_lossy());
+                rest = after;
+            }
+        }
+    }
+    while let Some(index) = rest.find('$') {
+        expanded.push_str(&rest[..index]);
+        rest = &rest[index + 1..];
+        if let Some(after) = rest.strip_prefix('$') {
+            expanded.push('$');
+            rest = after;
+            continue;
+        }
+        let (name, reference, after) = if let Some(braced) = rest.strip_prefix('{') {
+            let Some((name, after)) = braced.split_once('}') else {
+                expanded.push('$');
+                continue;
+            };
+            (name, &rest[..name.len() + 2], after)
+        } else {
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            (&rest[..end], &rest[..end], &rest[end..])
+        };
+        match env::var(name) {
+            Ok(value) if !name.is_empty() => expanded.push_str(&value),
+            _ => {
+                expanded.push('$');
+                expanded.push_str(reference);
+            }
+        }
+        rest = after;
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+impl SetValue {
+    /// Converts integers to floats and integral floats to integers when the option is of that
+    /// type.
+    #[allow(
+        clippy::cast_precision_loss,
+        clippy::cast_possible_truncation,
+        clippy::float_cmp
+    )]
+    pub fn coerce_to(self, current: &Self) -> Self {
+        match (current, self) {
+            (SetValue::Float(_), SetValue::Integer(value)) => SetValue::Float(value as f64),
+            (SetValue::Integer(_), SetValue::Float(value)) if value.trunc() == value => {
+                SetValue::Integer(value as i64)
+            }
+            (SetValue::Integer(_), SetValue::String(value)) => match parse_duration(&value) {
+                Some(milliseconds) => SetValue::Integer(milliseconds),
+                None => SetValue::String(value),
+            },
+            (_, value) => value,
+        }
+    }
+
+    /// Whether `self` cannot be coerced to the type of `current`, where lists and strings are
+    /// interchangeable.
+    fn mismatches(&self, current: &Self) -> bool {
+        let scalar = |value: &Self| {
+            matches!(
+                value,
+                SetValue::Bool(_) | SetValue::Integer(_) | SetValue::Float(_)
+            )
+        };
+        (scalar(self) || scalar(current))
+            && mem::discriminant(&self.clone().coerce_to(current)) != mem::discriminant(current)
+    }
+
+    /// Expands `~` and environment variables in string values.
+    pub fn expand(self) -> Self {
+        match self {
+            SetValue::String(value) => SetValue::String(expand_env(&value)),
+            SetValue::List(values) => {
+                SetValue::List(values.iter().map(|value| expand_env(value)).collect())
+            }
+            value => value,
+        }
+    }
+}
+
+impl From<bool> for SetValue {
+    fn from(value: bool) -> Self {
+        SetValue::Bool(value)
+    }
+}
+
+impl From<i64> for SetValue {
+    fn from(value: i64) -> Self {
+        SetValue::Integer(value)
+    }
+}
+
+impl From<f64> for SetValue {
+    fn from(value: f64) -> Self {
+        SetValue::Float(value)
+    }
+}
+
+impl From<&str> for SetValue {
+    fn from(value: &str) -> Self {
+        SetValue::String(value.to_owned())
+    }
+}
+
+impl From<String> for SetValue {
+    fn from(value: String) -> Self {
+        SetValue::String(value)
+    }
+}
+
+impl From<Vec<String>> for SetValue {
+    fn from(value: Vec<String>) -> Self {
+        SetValue::List(value)
+    }
+}
+
 impl ToObject for SetValue {
     fn to_object(self) -> Result<nvim_oxi::Object, conversion::Error> {
         match self {
@@ -185,61 +634,331 @@ impl ToObject for SetValue {
     }
 }
 
+/// Parses a number or a duration like `300ms`, `1.5s`, `2m` or `1h` into milliseconds, durations
+/// need to fit into an `i32`.
+#[allow(clippy::cast_possible_truncation)]
+fn parse_duration(value: &str) -> Option<i64> {
+    let value = value.trim();
+    if let Ok(number) = value.parse() {
+        return Some(number);
+    }
+    let (number, unit) = value.split_at(value.find(|c: char| !(c.is_ascii_digit() || c == '.'))?);
+    let number: f64 = number.parse().ok()?;
+    let factor = match unit.trim() {
+        "ms" => 1.,
+        "s" => 1_000.,
+        "m" | "min" => 60_000.,
+        "h" => 3_600_000.,
+        _ => return None,
+    };
+    let millis = (number * factor).round();
+    // checked, so the cast neither saturates nor truncates
+    (0. ..=f64::from(i32::MAX))
+        .contains(&millis)
+        .then_some(millis as i64)
+}
+
+/// Queries the info of option `name`, reporting unknown options unless `ignore_unknown_options`
+/// is set in `setup`, in which case they are skipped silently.
+pub fn option_info(name: &str) -> ApiResult<Option<OptionInfos>> {
+    match api::get_option_info(name) {
+        Ok(info) => Ok(Some(info)),
+        Err(_) if Setup::get().ignore_unknown_options => Ok(None),
+        Err(error) => {
+            log_error!("Invalid option {name}: {error}");
+            Ok(None)
+        }
+    }
+}
+
+thread_local! {
+    /// Option infos by option name, they do not change unless a plugin adds options.
+    static OPTION_INFOS: RefCell<HashMap<String, Rc<OptionInfos>>> = RefCell::default();
+}
+
+/// Process wide cache of option infos, so applying sets of an option only queries it once.
+pub struct OptionInfoCache;
+
+impl OptionInfoCache {
+    /// The info of option `name`, queried unless cached, `None` if the option does not exist.
+    pub fn get(name: &str) -> ApiResult<Option<Rc<OptionInfos>>> {
+        if let Some(info) = OPTION_INFOS.with(|infos| infos.borrow().get(name).cloned()) {
+            return Ok(Some(info));
+        }
+        let Some(info) = option_info(name)? else {
+            return Ok(None);
+        };
+        let info = Rc::new(info);
+        OPTION_INFOS.with(|infos| infos.borrow_mut().insert(name.to_owned(), info.clone()));
+        Ok(Some(info))
+    }
+
+    pub fn clear() {
+        OPTION_INFOS.with(|infos| infos.borrow_mut().clear());
+    }
+
+    /// Whether option `name` exists, without reporting it if it does not.
+    pub fn exists(name: &str) -> bool {
+        if OPTION_INFOS.with(|infos| infos.borrow().contains_key(name)) {
+            return true;
+        }
+        let Ok(info) = api::get_option_info(name) else {
+            return false;
+        };
+        OPTION_INFOS.with(|infos| infos.borrow_mut().insert(name.to_owned(), Rc::new(info)));
+        true
+    }
+}
+
+/// Order in which options of a scope are applied when batching.
+pub fn scope_order(scope: types::OptionScope) -> u8 {
+    match scope {
+        types::OptionScope::Global => 0,
+        types::OptionScope::Window => 1,
+        types::OptionScope::Buffer => 2,
+        _ => 3,
+    }
+}
+
 impl Set {
+    /// Parses a flag like `:set` does, `wrap` enables, `nowrap` disables and `invwrap` or
+    /// `wrap!` toggles the option. A leading `no` only disables if it is not part of the name.
+    pub fn flag(flag: &str) -> Self {
+        let negated = flag
+            .strip_prefix("no")
+            .filter(|name| !OptionInfoCache::exists(flag) && OptionInfoCache::exists(name));
+        let (name, operation, value) = if let Some(name) = negated {
+            (name, Operation::Assign, false)
+        } else if let Some(name) = flag.strip_prefix("inv").or_else(|| flag.strip_suffix('!')) {
+            (name, Operation::Toggle, true)
+        } else {
+            (flag, Operation::Assign, true)
+        };
+        Set(
+            name.to_owned(),
+            operation,
+            SetValue::Bool(value),
+            SetModifiers::default(),
+        )
+    }
+
+    pub fn name(&self) -> &str {
+        &self.0
+    }
+
+    /// Error for assigning a value the option does not accept, as far as its choices are known.
+    pub fn invalid_choice(&self) -> Option<String> {
+        let Set(key, Operation::Assign, SetValue::String(value), _) = self else {
+            return None;
+        };
+        let info = OptionInfoCache::get(key).ok()??;
+        invalid_choice(key, &info.name, value)
+    }
+
+    /// Whether the `if_has` feature gate, if any, is fulfilled.
+    pub fn enabled(&self) -> ApiResult<bool> {
+        if let Some(feature) = &self.3.if_has {
+            let has: i64 = api::call_function("has", (feature.as_str(),))?;
+            Ok(has != 0)
+        } else {
+            Ok(true)
+        }
+    }
+
+    /// Applies `sets` grouped by scope. Sets of the same option keep their order and are batched
+    /// into reading and setting the option once.
+    pub fn apply_all<'a>(sets: impl IntoIterator<Item = &'a Set>, buffer: bool) -> ApiResult {
+        let mut known = Vec::new();
+        for set in sets {
+            if !set.enabled()? {
+                continue;
+            }
+            if let Some(info) = OptionInfoCache::get(set.name())? {
+                known.push((set, info));
+            }
+        }
+        // the option and target a set modifies, aliases like `sw` included
+        let target =
+            |(set, info): &(&Set, Rc<OptionInfos>)| (info.name.clone(), set.3.buffer, set.3.scope);
+        let mut first = HashMap::new();
+        for (index, set) in known.iter().enumerate() {
+            first.entry(target(set)).or_insert(index);
+        }
+        let known = known
+            .into_iter()
+            .sorted_by_key(|set| (scope_order(set.1.scope), first[&target(set)]))
+            .collect_vec();
+        for (_, batch) in &known.iter().group_by(|&set| target(set)) {
+            let batch = batch.collect_vec();
+            let sets = batch.iter().map(|(set, _)| *set).collect_vec();
+            Self::apply_batch(&sets, buffer, &batch[0].1)?;
+        }
+        Ok(())
+    }
+
     pub fn apply(&self, buffer: bool) -> ApiResult {
-        let Set(key, op, value) = self;
+        if !self.enabled()? {
+            return Ok(());
+        }
+        let Some(infos) = OptionInfoCache::get(&self.0)? else {
+            return Ok(());
+        };
+        self.apply_with_info(buffer, &infos)
+    }
+
+    /// Applies the option using already queried `infos`, without checking `enabled`.
+    pub fn apply_with_info(&self, buffer: bool, infos: &OptionInfos) -> ApiResult {
+        Self::apply_batch(&[self], buffer, infos)
+    }
+
+    /// Applies `sets` of the same option and target one after another, reading the option
+    /// before the first and setting it after the last, without checking `enabled`.
+    fn apply_batch(sets: &[&Set], buffer: bool, infos: &OptionInfos) -> ApiResult {
+        let Some(Set(key, _, _, modifiers)) = sets.first() else {
+            return Ok(());
+        };
+        let buffer = match modifiers.buffer {
+            Some(BufferTarget::Number(number)) => {
+                // set in that buffer as it would be for the current one
+                let sets = sets
+                    .iter()
+                    .map(|&set| {
+                        let mut set = set.clone();
+                        set.3.buffer = Some(BufferTarget::Local(true));
+                        set
+                    })
+                    .collect_vec();
+                return Buffer::from(number).call(move |()| Set::apply_all(&sets, true));
+            }
+            Some(BufferTarget::Local(buffer)) => buffer,
+            None => buffer,
+        };
         let OptionInfos {
             commalist,
             flaglist,
-            name,
+            ref name,
             scope,
             ..
-        } = do_on_error!(
-            api::get_option_info(key),
-            return Ok(()),
-            error,
-            "Invalid option {key}: {error}"
-        );
-        let set_option = set_option(scope, buffer)?;
+        } = *infos;
+        let set_option = set_option(scope, buffer, modifiers.scope)?;
 
-        let get_option = get_option(scope, buffer)?;
+        let get_option = get_option(scope, buffer, modifiers.scope)?;
 
-        let current = SetValue::from_option(commalist, flaglist, &name, get_option(key)?)?;
+        let current = SetValue::from_option(commalist, flaglist, name, get_option(key)?)?;
+        record_snapshot(key, scope, buffer, modifiers.scope, &current);
 
-        match (current, value.clone(), op) {
-            (SetValue::Set(_), SetValue::List(value), Operation::Assign) => set_option(
-                key,
-                SetValue::Set(value.iter().flat_map(|s| s.chars()).collect()),
-            ),
-            (_, value, Operation::Assign) => set_option(key, value),
+        let mut value = None;
+        for set in sets {
+            let current = value.clone().unwrap_or_else(|| current.clone());
+            if let Some(resolved) = set.resolve(current, buffer, name)? {
+                // as the option would be read back, e.g. a string assigned to a comma list
+                value = Some(SetValue::from_option(
+                    commalist,
+                    flaglist,
+                    name,
+                    resolved.to_object()?,
+                )?);
+            }
+        }
+        let Some(value) = value else {
+            return Ok(());
+        };
+        set_option(key, value.clone()).or_else(|err| {
+            log_error!("Error while setting {key} to {value:?}: \n{err}");
+            Ok(())
+        })
+    }
+
+    /// The value of the option after applying this set to its `current` value, `None` if the
+    /// set is invalid, which is reported.
+    fn resolve(&self, current: SetValue, buffer: bool, name: &str) -> ApiResult<Option<SetValue>> {
+        let Set(key, op, value, modifiers) = self;
+        let mut resolved = value.clone().expand();
+        if let SetValue::String(reference) = &resolved {
+            if let Some(name) = reference.strip_prefix('&') {
+                let Some(value) = resolve_reference(name, buffer, modifiers.scope)? else {
+                    return Ok(None);
+                };
+                if value.mismatches(&current) {
+                    log_error!("{key} cannot reference {reference} of a different type");
+                    return Ok(None);
+                }
+                resolved = value;
+            }
+        }
+        let coerced = resolved.coerce_to(&current);
+        if let (SetValue::Map(map), false) = (&coerced, matches!(current, SetValue::Map(_))) {
+            if let Some(entry) = map.keys().find(|entry| suggest_set_key(entry).is_some()) {
+                log_error!("{}", unknown_set_key(key, entry));
+                return Ok(None);
+            }
+        }
+        if let (SetValue::Integer(_), SetValue::String(value)) = (&current, &coerced) {
+            log_error!("{key} expects a number or a duration like `300ms`, got `{value}`");
+            return Ok(None);
+        }
+        if let (Operation::Assign, SetValue::String(value)) = (op, &coerced) {
+            if let Some(error) = invalid_choice(key, name, value) {
+                log_error!("{error}");
+                return Ok(None);
+            }
+        }
+        let dedupe = modifiers
+            .dedupe
+            .unwrap_or_else(|| Setup::get().dedupe_lists);
+        Ok(match (current, coerced, op) {
+            (SetValue::Set(_), SetValue::List(value), Operation::Assign) => Some(SetValue::Set(
+                value.iter().flat_map(|s| s.chars()).collect(),
+            )),
+            (_, value, Operation::Assign) => Some(value),
+            (SetValue::Bool(current), _, Operation::Toggle) => Some(SetValue::Bool(!current)),
             (SetValue::Float(current), SetValue::Float(value), Operation::Append) => {
-                set_option(key, SetValue::Float(current + value))
+                Some(SetValue::Float(current + value))
             }
             (SetValue::Float(current), SetValue::Float(value), Operation::Remove) => {
-                set_option(key, SetValue::Float(value - current))
+                Some(SetValue::Float(current - value))
             }
             (SetValue::Integer(current), SetValue::Integer(value), Operation::Append) => {
-                set_option(key, SetValue::Integer(current + value))
+                Some(SetValue::Integer(current + value))
             }
             (SetValue::Integer(current), SetValue::Integer(value), Operation::Remove) => {
-                set_option(key, SetValue::Integer(value - current))
+                Some(SetValue::Integer(current - value))
             }
             (SetValue::String(current), SetValue::String(value), Operation::Append) => {
-                set_option(key, SetValue::String(current + &value))
+                Some(SetValue::String(current + &value))
             }
             (SetValue::String(current), SetValue::String(value), Operation::Prepend) => {
-                set_option(key, SetValue::String(value + &current))
+                Some(SetValue::String(value + &current))
             }
             (SetValue::String(current), SetValue::String(value), Operation::Remove) => {
-                set_option(key, SetValue::String(current.replacen(&value, "", 1)))
+                Some(SetValue::String(current.replacen(&value, "", 1)))
             }
-            (SetValue::List(mut current), SetValue::List(mut value), Operation::Append) => {
-                current.append(&mut value);
-                set_option(key, SetValue::List(current))
+            (SetValue::List(mut current), SetValue::List(value), Operation::Append) => {
+                for value in value {
+                    let end = current.len();
+                    push_list(&mut current, value, end, dedupe);
+                }
+                Some(SetValue::List(current))
             }
-            (SetValue::List(mut current), SetValue::List(mut value), Operation::Prepend) => {
-                value.append(&mut current);
-                set_option(key, SetValue::List(current))
+            (SetValue::List(mut current), SetValue::List(value), Operation::Prepend) => {
+                let mut index = 0;
+                for value in value {
+                    if push_list(&mut current, value, index, dedupe) {
+                        index += 1;
+                    }
+                }
+                Some(SetValue::List(current))
+            }
+            (SetValue::List(mut current), SetValue::List(values), Operation::Insert) => {
+                let Some(mut index) = insert_index(key, modifiers.index, current.len()) else {
+                    return Ok(None);
+                };
+                for value in values {
+                    if push_list(&mut current, value, index, dedupe) {
+                        index += 1;
+                    }
+                }
+                Some(SetValue::List(current))
             }
             (SetValue::List(mut current), SetValue::List(values), Operation::Remove) => {
                 for value in values {
@@ -247,21 +966,29 @@ impl Set {
                         current.remove(index);
                     }
                 }
-                set_option(key, SetValue::List(current))
+                Some(SetValue::List(current))
             }
             (SetValue::List(mut current), SetValue::String(value), Operation::Append) => {
-                current.push(value);
-                set_option(key, SetValue::List(current))
+                let end = current.len();
+                push_list(&mut current, value, end, dedupe);
+                Some(SetValue::List(current))
             }
             (SetValue::List(mut current), SetValue::String(value), Operation::Prepend) => {
-                current.insert(0, value);
-                set_option(key, SetValue::List(current))
+                push_list(&mut current, value, 0, dedupe);
+                Some(SetValue::List(current))
+            }
+            (SetValue::List(mut current), SetValue::String(value), Operation::Insert) => {
+                let Some(index) = insert_index(key, modifiers.index, current.len()) else {
+                    return Ok(None);
+                };
+                push_list(&mut current, value, index, dedupe);
+                Some(SetValue::List(current))
             }
             (SetValue::List(mut current), SetValue::String(value), Operation::Remove) => {
                 if let Some(index) = current.iter().position(|v| v == &value) {
                     current.remove(index);
                 }
-                set_option(key, SetValue::List(current))
+                Some(SetValue::List(current))
             }
             (
                 SetValue::Set(mut current),
@@ -269,11 +996,11 @@ impl Set {
                 Operation::Append | Operation::Prepend,
             ) => {
                 current.extend(value.chars());
-                set_option(key, SetValue::Set(current))
+                Some(SetValue::Set(current))
             }
             (SetValue::Set(mut current), SetValue::String(value), Operation::Remove) => {
                 current.retain(|&v| !value.contains(v));
-                set_option(key, SetValue::Set(current))
+                Some(SetValue::Set(current))
             }
             (
                 SetValue::Set(mut current),
@@ -281,13 +1008,13 @@ impl Set {
                 Operation::Append | Operation::Prepend,
             ) => {
                 current.extend(value.iter().flat_map(|s| s.chars()));
-                set_option(key, SetValue::Set(current))
+                Some(SetValue::Set(current))
             }
             (SetValue::Set(mut current), SetValue::List(values), Operation::Remove) => {
                 for value in values {
                     current.retain(|&v| !value.contains(v));
                 }
-                set_option(key, SetValue::Set(current))
+                Some(SetValue::Set(current))
             }
             (
                 SetValue::Map(mut current),
@@ -295,62 +1022,92 @@ impl Set {
                 Operation::Append | Operation::Prepend,
             ) => {
                 current.extend(value.into_iter());
-                set_option(key, SetValue::Map(current))
+                Some(SetValue::Map(current))
+            }
+            (
+                SetValue::Map(mut current),
+                SetValue::String(value),
+                Operation::Append | Operation::Prepend,
+            ) => {
+                let Some((entry, entry_value)) = value.split_once(':') else {
+                    log_error!("{key} should only contain map entries, got `{value}`");
+                    return Ok(None);
+                };
+                current.insert(entry.to_owned(), entry_value.to_owned());
+                Some(SetValue::Map(current))
             }
             (SetValue::Map(mut current), SetValue::List(values), Operation::Remove) => {
                 for value in values {
-                    current.remove(&value);
+                    remove_map_entries(&mut current, &value);
                 }
-                set_option(key, SetValue::Map(current))
+                Some(SetValue::Map(current))
             }
             (SetValue::Map(mut current), SetValue::String(value), Operation::Remove) => {
-                current.remove(&value);
-                set_option(key, SetValue::Map(current))
+                remove_map_entries(&mut current, &value);
+                Some(SetValue::Map(current))
             }
             (current, value, op) => {
                 log_error!("{op} {value:?} to {current:?} of {key} is not supported");
-                return Ok(());
+                None
             }
-        }
-        .or_else(|err| {
-            log_error!("Error while {op} {value:?} to {key}: \n{err}");
-            Ok(())
         })
     }
 }
 
-fn set_option(
+/// Removes the entry `key` from `map`, or all entries starting with it if it ends in `*`.
+fn remove_map_entries(map: &mut HashMap<String, String>, key: &str) {
+    if let Some(prefix) = key.strip_suffix('*') {
+        map.retain(|entry, _| !entry.starts_with(prefix));
+    } else {
+        map.remove(key);
+    }
+}
+
+/// Inserts `value` into `list` at `index`, unless it is already contained and `dedupe` is set.
+/// Returns whether `value` was inserted.
+fn push_list(list: &mut Vec<String>, value: String, index: usize, dedupe: bool) -> bool {
+    if dedupe && list.contains(&value) {
+        false
+    } else {
+        list.insert(index, value);
+        true
+    }
+}
+
+/// Index of `list` to insert into for [`Operation::Insert`], clamped to `len`.
+fn insert_index(key: &str, index: Option<i64>, len: usize) -> Option<usize> {
+    let Some(index) = index else {
+        log_error!("Inserting into {key} needs an `index`");
+        return None;
+    };
+    match usize::try_from(index) {
+        Ok(index) => Some(index.min(len)),
+        Err(_) => {
+            log_error!("Cannot insert into {key} at negative index {index}");
+            None
+        }
+    }
+}
+
+/// Scope of `set_option_value`/`get_option_value` for an option of `scope`, `None` to set both
+/// the global and the local value like `:set`.
+fn value_scope(
     scope: types::OptionScope,
     buffer: bool,
-) -> ApiResult<fn(name: &str, value: SetValue) -> Result<()>> {
+    hint: Option<SetScope>,
+    set: bool,
+) -> ApiResult<Option<OptionScope>> {
+    if let Some(hint) = hint {
+        return Ok(Some(match hint {
+            SetScope::Local => OptionScope::Local,
+            SetScope::Global => OptionScope::Global,
+        }));
+    }
     Ok(match scope {
-        types::OptionScope::Buffer if !buffer => |name, value| {
-            api::set_option(name, value.clone())?;
-            Buffer::current()
-                .set_option(name, value)
-                .map_err(Into::into)
-        },
-        types::OptionScope::Buffer => |name, value| {
-            Buffer::current()
-                .set_option(name, value)
-                .map_err(Into::into)
-        },
-        types::OptionScope::Global if buffer => |name, value: SetValue| {
-            api::set_option_value(
-                name,
-                value,
-                &OptionValueOpts::builder().scope(OptionScope::Local).build(),
-            )
-            .map_err(Into::into)
-        },
-        types::OptionScope::Global => {
-            |name, value: SetValue| api::set_option(name, value).map_err(Into::into)
-        }
-        types::OptionScope::Window => |name, value| {
-            Window::current()
-                .set_option(name, value)
-                .map_err(Into::into)
-        },
+        types::OptionScope::Buffer if set && !buffer => None,
+        types::OptionScope::Buffer | types::OptionScope::Window => Some(OptionScope::Local),
+        types::OptionScope::Global if buffer => Some(OptionScope::Local),
+        types::OptionScope::Global => Some(OptionScope::Global),
         _ => {
             return Err(ApiError::Other(format!(
                 "Unsuported Option scope: {scope:?}"
@@ -359,34 +1116,470 @@ fn set_option(
     })
 }
 
+fn option_value_opts(scope: Option<OptionScope>) -> OptionValueOpts {
+    let mut opts = OptionValueOpts::builder();
+    if let Some(scope) = scope {
+        opts.scope(scope);
+    }
+    opts.build()
+}
+
+fn set_option(
+    scope: types::OptionScope,
+    buffer: bool,
+    hint: Option<SetScope>,
+) -> ApiResult<impl Fn(&str, SetValue) -> Result<()>> {
+    let opts = option_value_opts(value_scope(scope, buffer, hint, true)?);
+    Ok(move |name: &str, value: SetValue| {
+        api::set_option_value(name, value, &opts).map_err(Into::into)
+    })
+}
+
+/// Current value of option `name`, referenced as `&name` by a set.
+fn resolve_reference(
+    name: &str,
+    buffer: bool,
+    hint: Option<SetScope>,
+) -> ApiResult<Option<SetValue>> {
+    let Some(info) = OptionInfoCache::get(name)? else {
+        return Ok(None);
+    };
+    let value = get_option(info.scope, buffer, hint)?(name)?;
+    Ok(Some(SetValue::from_option(
+        info.commalist,
+        info.flaglist,
+        &info.name,
+        value,
+    )?))
+}
+
 fn get_option(
     scope: types::OptionScope,
     buffer: bool,
-) -> ApiResult<fn(name: &str) -> ApiResult<Object>> {
-    // dbg!((scope, buffer));
-    Ok(match scope {
-        types::OptionScope::Buffer => |name| Buffer::current().get_option(name),
-        types::OptionScope::Global if buffer => |name| {
-            api::get_option_value(
-                name,
-                &OptionValueOpts::builder().scope(OptionScope::Local).build(),
+    hint: Option<SetScope>,
+) -> ApiResult<impl Fn(&str) -> ApiResult<Object>> {
+    let opts = option_value_opts(value_scope(scope, buffer, hint, false)?);
+    Ok(move |name: &str| api::get_option_value(name, &opts))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assign(name: &str, value: impl Into<SetValue>) -> Set {
+        Set(
+            name.to_owned(),
+            Operation::Assign,
+            value.into(),
+            SetModifiers::default(),
+        )
+    }
+
+    #[test]
+    fn expands_env() {
+        env::set_var("CONFIG_NVIM_TEST_DIR", "/opt/config");
+        env::remove_var("CONFIG_NVIM_UNDEFINED");
+        let home = home::home_dir().expect("home is known");
+        assert_eq!(
+            expand_env("~/.cache"),
+            format!("{}/.cache", home.to_string_lossy())
+        );
+        assert_eq!(expand_env("~user/.cache"), "~user/.cache");
+        assert_eq!(expand_env("$CONFIG_NVIM_TEST_DIR/undo"), "/opt/config/undo");
+        assert_eq!(expand_env("${CONFIG_NVIM_TEST_DIR}undo"), "/opt/configundo");
+        assert_eq!(
+            expand_env("$$CONFIG_NVIM_TEST_DIR"),
+            "$CONFIG_NVIM_TEST_DIR"
+        );
+        assert_eq!(
+            expand_env("$CONFIG_NVIM_UNDEFINED/x"),
+            "$CONFIG_NVIM_UNDEFINED/x"
+        );
+        assert_eq!(expand_env("${unterminated"), "${unterminated");
+        assert_eq!(expand_env("rg $*"), "rg $*");
+    }
+
+    #[test]
+    fn removes_map_entries() {
+        let listchars = || -> HashMap<String, String> {
+            ["tab:>-", "trail:~", "lead:.", "leadmultispace:x"]
+                .iter()
+                .filter_map(|entry| entry.split_once(':'))
+                .map(|(key, value)| (key.to_owned(), value.to_owned()))
+                .collect()
+        };
+        let keys = |map: HashMap<String, String>| map.into_keys().sorted().collect_vec();
+
+        let mut map = listchars();
+        remove_map_entries(&mut map, "lead");
+        assert_eq!(keys(map), ["leadmultispace", "tab", "trail"]);
+        let mut map = listchars();
+        remove_map_entries(&mut map, "lead*");
+        assert_eq!(keys(map), ["tab", "trail"]);
+        let mut map = listchars();
+        remove_map_entries(&mut map, "eol");
+        remove_map_entries(&mut map, "eol*");
+        assert_eq!(keys(map), ["lead", "leadmultispace", "tab", "trail"]);
+        let mut map = listchars();
+        remove_map_entries(&mut map, "*");
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn remove_subtracts_from_numbers() {
+        let remove = |value, current| {
+            Set(
+                "scrolloff".to_owned(),
+                Operation::Remove,
+                value,
+                SetModifiers::default(),
             )
-        },
-        types::OptionScope::Global => |name| {
-            api::get_option_value(
-                name,
-                &OptionValueOpts::builder()
-                    .scope(OptionScope::Global)
-                    .build(),
+            .resolve(current, false, "scrolloff")
+            .expect("numbers are resolved")
+        };
+        assert_eq!(
+            remove(SetValue::Integer(2), SetValue::Integer(10)),
+            Some(SetValue::Integer(8))
+        );
+        assert_eq!(
+            remove(SetValue::Float(0.5), SetValue::Float(2.0)),
+            Some(SetValue::Float(1.5))
+        );
+    }
+
+    #[test]
+    fn suggests_set_keys() {
+        assert_eq!(distance("", ""), 0);
+        assert_eq!(distance("append", ""), 6);
+        assert_eq!(distance("kitten", "sitting"), 3);
+        assert_eq!(distance("apend", "append"), 1);
+        assert_eq!(distance("remvoe", "remove"), 2);
+
+        assert_eq!(suggest_set_key("apend"), Some("append"));
+        assert_eq!(suggest_set_key("prepnd"), Some("prepend"));
+        assert_eq!(suggest_set_key("idnex"), Some("index"));
+        assert_eq!(suggest_set_key("append"), None);
+        assert_eq!(suggest_set_key("shiftwidth"), None);
+        assert_eq!(
+            unknown_set_key("wrap", "toggel"),
+            "unknown operation `toggel` for wrap, did you mean `toggle`?"
+        );
+    }
+
+    #[nvim_oxi::test]
+    fn undo_restores_the_last_snapshot() {
+        let textwidth = || {
+            api::get_option_value::<i64>("textwidth", &OptionValueOpts::default())
+                .expect("textwidth is readable")
+        };
+        snapshot(|| assign("textwidth", 60_i64).apply(false)).expect("first apply succeeds");
+        snapshot(|| assign("textwidth", 70_i64).apply(false)).expect("second apply succeeds");
+        // like a conditional config applied after loading
+        assign("textwidth", 80_i64)
+            .apply(false)
+            .expect("later apply succeeds");
+        assign("shiftwidth", 3_i64)
+            .apply(true)
+            .expect("buffer apply succeeds");
+        assert_eq!(undo_snapshot().expect("undo succeeds"), 1);
+        assert_eq!(textwidth(), 60);
+        assert_eq!(undo_snapshot().expect("second undo succeeds"), 0);
+    }
+
+    #[nvim_oxi::test]
+    fn prepend_keeps_order() {
+        let prepend = |values: &[&str], dedupe| {
+            Set(
+                "wildignore".to_owned(),
+                Operation::Prepend,
+                values
+                    .iter()
+                    .map(|&value| value.to_owned())
+                    .collect::<Vec<_>>()
+                    .into(),
+                SetModifiers {
+                    dedupe: Some(dedupe),
+                    ..SetModifiers::default()
+                },
             )
-        },
-        types::OptionScope::Window => {
-            |name| -> ApiResult<Object> { Window::current().get_option(name) }
-        }
-        _ => {
-            return Err(api::Error::Other(format!(
-                "Unsuported Option scope: {scope:?}"
-            )))
-        }
-    })
+            .apply(false)
+            .expect("prepend is applied");
+            api::get_option_value::<String>("wildignore", &OptionValueOpts::default())
+                .expect("wildignore is readable")
+        };
+        assign("wildignore", "c")
+            .apply(false)
+            .expect("wildignore is assigned");
+        assert_eq!(prepend(&["a", "b"], false), "a,b,c");
+        assert_eq!(prepend(&["b", "d"], true), "d,a,b,c");
+        assert_eq!(prepend(&["a"], false), "a,d,a,b,c");
+    }
+
+    #[nvim_oxi::test]
+    fn option_infos_are_cached() {
+        let info = OptionInfoCache::get("tabstop")
+            .expect("query succeeds")
+            .expect("tabstop exists");
+        let cached = OptionInfoCache::get("tabstop")
+            .expect("query succeeds")
+            .expect("tabstop exists");
+        assert!(Rc::ptr_eq(&info, &cached));
+        OptionInfoCache::clear();
+        let queried = OptionInfoCache::get("tabstop")
+            .expect("query succeeds")
+            .expect("tabstop exists");
+        assert!(!Rc::ptr_eq(&info, &queried));
+    }
+
+    #[nvim_oxi::test]
+    fn flags_negate_existing_options() {
+        let flag = |flag| {
+            let Set(name, op, value, _) = Set::flag(flag);
+            (name, op, value)
+        };
+        assert_eq!(
+            flag("nowrap"),
+            ("wrap".to_owned(), Operation::Assign, SetValue::Bool(false))
+        );
+        assert_eq!(
+            flag("nosuchoption"),
+            (
+                "nosuchoption".to_owned(),
+                Operation::Assign,
+                SetValue::Bool(true)
+            )
+        );
+        assert!(logged_messages().is_empty());
+    }
+
+    #[nvim_oxi::test]
+    fn scopes_set_the_same_values() {
+        let value = |name, scope| -> i64 {
+            let mut opts = OptionValueOpts::builder();
+            if let Some(scope) = scope {
+                opts.scope(scope);
+            }
+            api::get_option_value(name, &opts.build()).expect("option is readable")
+        };
+        // global
+        assign("laststatus", 2_i64)
+            .apply(false)
+            .expect("global option is set");
+        assert_eq!(value("laststatus", None), 2);
+        // global-local
+        assign("scrolloff", 4_i64)
+            .apply(true)
+            .expect("local value of a global-local option is set");
+        assert_eq!(value("scrolloff", Some(OptionScope::Local)), 4);
+        // window
+        assign("numberwidth", 6_i64)
+            .apply(false)
+            .expect("window option is set");
+        assert_eq!(value("numberwidth", Some(OptionScope::Local)), 6);
+        // buffer
+        assign("shiftwidth", 5_i64)
+            .apply(false)
+            .expect("buffer option is set globally");
+        assign("shiftwidth", 3_i64)
+            .apply(true)
+            .expect("buffer option is set locally");
+        assert_eq!(value("shiftwidth", Some(OptionScope::Global)), 5);
+        assert_eq!(value("shiftwidth", Some(OptionScope::Local)), 3);
+        // hint
+        Set(
+            "shiftwidth".to_owned(),
+            Operation::Assign,
+            SetValue::Integer(7),
+            SetModifiers {
+                scope: Some(SetScope::Global),
+                ..SetModifiers::default()
+            },
+        )
+        .apply(true)
+        .expect("global value of a buffer option is set");
+        assert_eq!(value("shiftwidth", Some(OptionScope::Global)), 7);
+        assert_eq!(value("shiftwidth", Some(OptionScope::Local)), 3);
+    }
+
+    #[nvim_oxi::test]
+    fn batching_keeps_results() {
+        let sets = [
+            assign("shiftwidth", 2_i64),
+            assign("number", true),
+            Set(
+                "shiftwidth".to_owned(),
+                Operation::Append,
+                SetValue::Integer(2),
+                SetModifiers::default(),
+            ),
+            assign("laststatus", 3_i64),
+        ];
+        Set::apply_all(&sets, false).expect("sets are applied");
+        let opts = OptionValueOpts::default();
+        let shiftwidth: i64 =
+            api::get_option_value("shiftwidth", &opts).expect("shiftwidth is readable");
+        let number: bool = api::get_option_value("number", &opts).expect("number is readable");
+        let laststatus: i64 =
+            api::get_option_value("laststatus", &opts).expect("laststatus is readable");
+        assert_eq!((shiftwidth, number, laststatus), (4, true, 3));
+    }
+
+    #[nvim_oxi::test]
+    fn batches_follow_each_other() {
+        let list = |op, values: &[&str]| {
+            Set(
+                "wildignore".to_owned(),
+                op,
+                values
+                    .iter()
+                    .map(|&value| value.to_owned())
+                    .collect::<Vec<_>>()
+                    .into(),
+                SetModifiers::default(),
+            )
+        };
+        let sets = [
+            assign("wildignore", "a"),
+            assign("textwidth", 80_i64),
+            list(Operation::Append, &["b", "c"]),
+            list(Operation::Remove, &["a"]),
+            assign("tw", 90_i64),
+            list(Operation::Prepend, &["d"]),
+        ];
+        Set::apply_all(&sets, false).expect("sets are applied");
+        let opts = OptionValueOpts::default();
+        let wildignore: String =
+            api::get_option_value("wildignore", &opts).expect("wildignore is readable");
+        let textwidth: i64 =
+            api::get_option_value("textwidth", &opts).expect("textwidth is readable");
+        assert_eq!((wildignore.as_str(), textwidth), ("d,b,c", 90));
+    }
+
+    #[nvim_oxi::test]
+    fn references_use_the_current_value() {
+        let opts = OptionValueOpts::default();
+        api::set_option_value("tabstop", 6_i64, &opts).expect("tabstop is set");
+        Set::apply_all(
+            &[
+                assign("shiftwidth", "&tabstop"),
+                assign("textwidth", "&filetype"),
+            ],
+            false,
+        )
+        .expect("sets are applied");
+        let shiftwidth: i64 =
+            api::get_option_value("shiftwidth", &opts).expect("shiftwidth is readable");
+        let textwidth: i64 =
+            api::get_option_value("textwidth", &opts).expect("textwidth is readable");
+        assert_eq!((shiftwidth, textwidth), (6, 0));
+        assert_eq!(
+            logged_messages(),
+            ["textwidth cannot reference &filetype of a different type"]
+        );
+    }
+
+    #[nvim_oxi::test]
+    fn background_choices() {
+        assign("background", "dark")
+            .apply(false)
+            .expect("valid choice is applied");
+        assign("background", "drak")
+            .apply(false)
+            .expect("invalid choice is reported instead of failing");
+        let background: String = api::get_option_value("background", &OptionValueOpts::default())
+            .expect("background is readable");
+        assert_eq!(background, "dark");
+        assert!(logged_messages()
+            .iter()
+            .any(|message| message == "Invalid value `drak` for background, expected dark|light"));
+    }
+
+    #[nvim_oxi::test]
+    fn unknown_options_can_be_ignored() {
+        let unknown = || option_info("config_nvim_unknown").expect("query succeeds");
+        assert!(unknown().is_none());
+        assert_eq!(logged_messages().len(), 1);
+
+        setup(Object::from(Dictionary::from_iter([(
+            "ignore_unknown_options",
+            true,
+        )])))
+        .expect("setup is valid");
+        assert!(unknown().is_none());
+        assert_eq!(logged_messages().len(), 1);
+    }
+
+    #[test]
+    fn insert_at_index() {
+        let sets = |source| {
+            toml::from_str::<SetsDeserializer>(source)
+                .map_err(|error| error.to_string())
+                .and_then(Vec::<Set>::try_from)
+        };
+        let set = sets("path = { insert = { index = 1, value = \"src\" } }")
+            .expect("insert table is valid");
+        let [Set(name, Operation::Insert, SetValue::String(value), modifiers)] = &set[..] else {
+            panic!("expected one insert, got {set:?}");
+        };
+        assert_eq!(
+            (&name[..], &value[..], modifiers.index),
+            ("path", "src", Some(1))
+        );
+        assert!(sets("path = { append = { index = 1, value = \"src\" } }").is_err());
+
+        assert_eq!(insert_index("path", Some(0), 3), Some(0));
+        assert_eq!(insert_index("path", Some(2), 3), Some(2));
+        assert_eq!(insert_index("path", Some(5), 3), Some(3));
+    }
+
+    #[nvim_oxi::test]
+    fn insert_at_negative_index() {
+        assert_eq!(insert_index("path", Some(-1), 3), None);
+        assert!(logged_messages()
+            .iter()
+            .any(|message| message == "Cannot insert into path at negative index -1"));
+    }
+
+    #[test]
+    fn comments_are_ignored_in_operations() {
+        let sets: Vec<Set> = toml::from_str::<SetsDeserializer>(
+            "_note = \"prose\"\nwrap = { _note = \"soft\", assign = false }",
+        )
+        .expect("sets are valid")
+        .try_into()
+        .expect("sets are valid");
+        assert_eq!(
+            sets.iter()
+                .map(|Set(name, op, value, _)| (name.as_str(), *op, value))
+                .collect_vec(),
+            [("wrap", Operation::Assign, &SetValue::Bool(false))]
+        );
+    }
+
+    #[test]
+    fn invalid_choice_lists_choices() {
+        assert_eq!(
+            invalid_choice("bg", "background", "drak").as_deref(),
+            Some("Invalid value `drak` for bg, expected dark|light")
+        );
+        assert_eq!(invalid_choice("background", "background", "dark"), None);
+        assert_eq!(invalid_choice("signcolumn", "signcolumn", "yes:2"), None);
+        assert!(invalid_choice("signcolumn", "signcolumn", "yes:x").is_some());
+        assert_eq!(invalid_choice("grepprg", "grepprg", "rg"), None);
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("42"), Some(42));
+        assert_eq!(parse_duration("300ms"), Some(300));
+        assert_eq!(parse_duration("1.5s"), Some(1_500));
+        assert_eq!(parse_duration("2m"), Some(120_000));
+        assert_eq!(parse_duration("2 min"), Some(120_000));
+        assert_eq!(parse_duration("1h"), Some(3_600_000));
+        assert_eq!(parse_duration("2d"), None);
+        assert_eq!(parse_duration("ms"), None);
+        assert_eq!(parse_duration("1.2.3s"), None);
+        assert_eq!(parse_duration("1000h"), None);
+        assert_eq!(parse_duration("1e400s"), None);
+    }
 }
@@ -0,0 +1,53 @@
+use oxi::api::Window;
+use serde::Serialize;
+
+use crate::*;
+
+/// Converts `value` for a variable, unlike for options lists and maps are kept as they are.
+fn variable(value: &SetValue) -> ApiResult<Object> {
+    value
+        .clone()
+        .expand()
+        .serialize(oxi::serde::Serializer::new())
+        .map_err(|error| ApiError::Other(error.to_string()))
+}
+
+/// Sets window variables (`w:`) on the current window.
+pub fn apply_window_vars(vars: &HashMap<String, SetValue>) -> ApiResult {
+    let mut window = Window::current();
+    for (name, value) in vars {
+        window.set_var(name, variable(value)?)?;
+    }
+    Ok(())
+}
+
+/// Sets tabpage variables (`t:`) on the current tabpage.
+pub fn apply_tab_vars(vars: &HashMap<String, SetValue>) -> ApiResult {
+    let mut tabpage = api::get_current_tabpage();
+    for (name, value) in vars {
+        tabpage.set_var(name, variable(value)?)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[nvim_oxi::test]
+    fn variables_keep_lists() {
+        let list = SetValue::List(vec!["a".to_owned(), "b".to_owned()]);
+        apply_window_vars(&HashMap::from([("list".to_owned(), list.clone())]))
+            .expect("window variable is set");
+        apply_tab_vars(&HashMap::from([("list".to_owned(), list)]))
+            .expect("tabpage variable is set");
+        let window: Vec<String> = Window::current()
+            .get_var("list")
+            .expect("window variable is readable");
+        let tabpage: Vec<String> = api::get_current_tabpage()
+            .get_var("list")
+            .expect("tabpage variable is readable");
+        assert_eq!(window, ["a", "b"]);
+        assert_eq!(tabpage, ["a", "b"]);
+    }
+}
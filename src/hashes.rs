@@ -1,7 +1,10 @@
 use std::{
+    cell::{Cell, RefCell},
     collections::HashMap,
     fs,
+    io::{self, Write},
     path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
 };
 
 use serde::{Deserialize, Serialize};
@@ -9,44 +12,271 @@ use sha2::{Digest, Sha512};
 
 use crate::*;
 
-fn hashes_file() -> PathBuf {
-    let stdpath: String =
-        api::call_function("stdpath", ("data",)).expect("There is a stdpath for data");
-    PathBuf::from(stdpath).join("config/hashes")
+/// Path of the hashes file, `hashes_file` from `setup` or `config/hashes` in `stdpath("data")`.
+fn hashes_file() -> ApiResult<PathBuf> {
+    if let Some(path) = Setup::get().hashes_file {
+        return Ok(PathBuf::from(expand_env(&path)));
+    }
+    let stdpath: String = api::call_function("stdpath", ("data",)).map_err(|e| {
+        ApiError::Other(format!(
+            "Unable to locate the data dir for the hashes file: {e}"
+        ))
+    })?;
+    Ok(PathBuf::from(stdpath).join("config/hashes"))
+}
+
+pub trait ConfigHasher {
+    fn hash(&self, content: &str) -> Vec<u8>;
+}
+
+pub struct Sha512Hasher;
+impl ConfigHasher for Sha512Hasher {
+    fn hash(&self, content: &str) -> Vec<u8> {
+        let mut hasher = Sha512::new();
+        hasher.update(content);
+        hasher.finalize().to_vec()
+    }
+}
+
+/// Non-cryptographic, but cheap and stable across platforms and rust versions.
+pub struct Fnv1aHasher;
+impl ConfigHasher for Fnv1aHasher {
+    fn hash(&self, content: &str) -> Vec<u8> {
+        content
+            .bytes()
+            .fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+                (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+            })
+            .to_be_bytes()
+            .to_vec()
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HashAlgorithm {
+    #[default]
+    Sha512,
+    Fnv1a,
+}
+
+impl HashAlgorithm {
+    pub fn hasher(self) -> &'static dyn ConfigHasher {
+        match self {
+            HashAlgorithm::Sha512 => &Sha512Hasher,
+            HashAlgorithm::Fnv1a => &Fnv1aHasher,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Entry {
+    pub hash: Vec<u8>,
+    /// Algorithm `hash` was computed with, entries from before this was stored used SHA-512.
+    #[serde(default)]
+    pub algorithm: HashAlgorithm,
+    /// Seconds since the unix epoch when the config was allowed.
+    #[serde(default)]
+    pub approved: Option<i64>,
+    /// Content of the config when it was allowed.
+    #[serde(default)]
+    pub snapshot: Option<String>,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+enum HashesFile {
+    Versioned {
+        version: u32,
+        entries: HashMap<PathBuf, Entry>,
+        #[serde(default)]
+        trusted_dirs: Vec<PathBuf>,
+    },
+    /// Format before versioning, only containing the hashes.
+    Legacy(HashMap<PathBuf, Vec<u8>>),
+}
+
+impl From<HashesFile> for Hashes {
+    fn from(file: HashesFile) -> Self {
+        match file {
+            HashesFile::Versioned {
+                entries,
+                trusted_dirs,
+                ..
+            } => Self {
+                entries,
+                trusted_dirs,
+            },
+            HashesFile::Legacy(hashes) => Self {
+                entries: hashes
+                    .into_iter()
+                    .map(|(path, hash)| {
+                        (
+                            path,
+                            Entry {
+                                hash,
+                                algorithm: HashAlgorithm::Sha512,
+                                approved: None,
+                                snapshot: None,
+                            },
+                        )
+                    })
+                    .collect(),
+                trusted_dirs: Vec::new(),
+            },
+        }
+    }
+}
+
+impl From<Hashes> for HashesFile {
+    fn from(
+        Hashes {
+            entries,
+            trusted_dirs,
+        }: Hashes,
+    ) -> Self {
+        Self::Versioned {
+            version: 2,
+            entries,
+            trusted_dirs,
+        }
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug, Default, Clone)]
-pub struct Hashes(HashMap<PathBuf, Vec<u8>>);
+#[serde(from = "HashesFile", into = "HashesFile")]
+pub struct Hashes {
+    entries: HashMap<PathBuf, Entry>,
+    /// Directories all configs in are trusted regardless of their content.
+    trusted_dirs: Vec<PathBuf>,
+}
+
+/// `path` the way trust is stored for it, so a config reached through a symlink or `..` is the
+/// same one, as given if it does not exist (anymore).
+fn canonical(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_owned())
+}
+
+thread_local! {
+    /// In-memory trust state, only refreshed from disk by [`Hashes::reload`].
+    static HASHES: RefCell<Hashes> = RefCell::default();
+    /// Whether the hashes file could not be read, it is not overwritten until trust is reset.
+    static CORRUPT: Cell<bool> = Cell::default();
+}
+
 impl Hashes {
+    pub fn with<R>(f: impl FnOnce(&mut Hashes) -> R) -> R {
+        HASHES.with(|hashes| f(&mut hashes.borrow_mut()))
+    }
+
+    pub fn reload() {
+        let hashes = match Self::load() {
+            Ok(hashes) => {
+                CORRUPT.with(|corrupt| corrupt.set(false));
+                hashes.unwrap_or_default()
+            }
+            Err(error) => {
+                CORRUPT.with(|corrupt| corrupt.set(true));
+                log_error!(
+                    "{error}\nNo local config is trusted, run :ConfigRevoke to reset the trust \
+                     of all local configs and start over"
+                );
+                Self::default()
+            }
+        };
+        HASHES.with(|current| *current.borrow_mut() = hashes);
+    }
+
+    /// Distrusts all configs, replacing even a corrupt hashes file on the next save.
+    pub fn reset(&mut self) {
+        *self = Self::default();
+        CORRUPT.with(|corrupt| corrupt.set(false));
+    }
+
     pub fn is_hashed(&self, path: &Path, config: &str) -> bool {
-        if let Some(hash) = self.0.get(path) {
-            let mut hasher = Sha512::new();
-            hasher.update(config);
-            let result = hasher.finalize();
-            hash == result.as_slice()
+        if self.trusted_dir(path).is_some() {
+            true
+        } else if let Some(entry) = self.entry(path) {
+            entry.hash == entry.algorithm.hasher().hash(config)
         } else {
             false
         }
     }
     pub fn add_hash(&mut self, path: PathBuf, config: &str) {
-        self.0.insert(path, {
-            let mut hasher = Sha512::new();
-            hasher.update(config);
-            hasher.finalize().to_vec()
-        });
+        let algorithm = Setup::get().hash_algorithm;
+        self.entries.insert(
+            canonical(&path),
+            Entry {
+                hash: algorithm.hasher().hash(config),
+                algorithm,
+                approved: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .ok()
+                    .and_then(|duration| i64::try_from(duration.as_secs()).ok()),
+                snapshot: Some(config.to_owned()),
+            },
+        );
+    }
+
+    pub fn entry(&self, path: &Path) -> Option<&Entry> {
+        self.entries.get(&canonical(path))
+    }
+
+    pub fn remove_hash(&mut self, path: &Path) -> bool {
+        let path = canonical(path);
+        let trusted_dirs = self.trusted_dirs.len();
+        self.trusted_dirs.retain(|dir| *dir != path);
+        self.entries.remove(&path).is_some() || self.trusted_dirs.len() != trusted_dirs
     }
 
-    pub fn load() -> Option<Self> {
-        rmp_serde::from_slice(&fs::read(hashes_file()).ok()?).ok()?
+    pub fn paths(&self) -> impl Iterator<Item = &Path> {
+        self.entries.keys().map(PathBuf::as_path)
+    }
+
+    pub fn trusted_dirs(&self) -> impl Iterator<Item = &Path> {
+        self.trusted_dirs.iter().map(PathBuf::as_path)
+    }
+
+    /// The trusted directory containing `path`, if any.
+    ///
+    /// Compares canonical paths, so neither `..` nor a symlink leads out of a trusted directory.
+    pub fn trusted_dir(&self, path: &Path) -> Option<&Path> {
+        let path = canonical(path);
+        self.trusted_dirs().find(|dir| path.starts_with(dir))
+    }
+
+    pub fn trust_dir(&mut self, dir: PathBuf) {
+        let dir = canonical(&dir);
+        if !self.trusted_dirs.contains(&dir) {
+            self.trusted_dirs.push(dir);
+        }
+    }
+
+    /// Reads the hashes file, `None` if there is none yet.
+    pub fn load() -> Result<Option<Self>, String> {
+        let hashes_file = hashes_file().map_err(|error| error.to_string())?;
+        let content = match fs::read(&hashes_file) {
+            Ok(content) => content,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => {
+                return Err(format!(
+                    "Error while reading hashes `{}`: {error}",
+                    hashes_file.display()
+                ))
+            }
+        };
+        rmp_serde::from_slice(&content)
+            .map(Some)
+            .map_err(|error| format!("Hashes `{}` are corrupt: {error}", hashes_file.display()))
     }
 
     pub fn unhashed(
         &self,
         files: impl IntoIterator<Item = (PathBuf, String, Config)>,
-    ) -> (Vec<PathBuf>, Vec<Config>) {
+    ) -> (Vec<PathBuf>, Vec<(PathBuf, Config)>) {
         files.into_iter().partition_map(|(path, string, config)| {
             if self.is_hashed(&path, &string) {
-                Either::Right(config)
+                Either::Right((path, config))
             } else {
                 Either::Left(path)
             }
@@ -54,24 +284,229 @@ impl Hashes {
     }
 
     pub fn save(&self) -> ApiResult<()> {
-        let hashes_file = hashes_file();
-        let data_dir = hashes_file.parent().expect("Hashes file has a parent");
-        fs::create_dir_all(data_dir).map_err(|e| {
-            api::Error::Other(format!(
-                "Error while creating data dir `{}`: {e}",
-                data_dir.display()
-            ))
-        })?;
-        fs::write(
-            &hashes_file,
-            rmp_serde::to_vec(&self).expect("Hashes serialization is infallible"),
-        )
-        .map_err(|e| {
-            api::Error::Other(format!(
-                "Error while saving hashes `{}`: {e}",
+        let hashes_file = hashes_file()?;
+        if CORRUPT.with(Cell::get) {
+            return Err(api::Error::Other(format!(
+                "Not overwriting the corrupt hashes `{}`, run :ConfigRevoke to reset them",
                 hashes_file.display()
-            ))
-        })?;
+            )));
+        }
+        if let Some(data_dir) = hashes_file.parent() {
+            fs::create_dir_all(data_dir).map_err(|e| {
+                api::Error::Other(format!(
+                    "Error while creating data dir `{}`: {e}",
+                    data_dir.display()
+                ))
+            })?;
+        }
+        // written next to the hashes and moved over them, so they are never left half written
+        let temporary = hashes_file.with_extension("tmp");
+        fs::File::create(&temporary)
+            .and_then(|mut file| {
+                file.write_all(
+                    &rmp_serde::to_vec_named(&self).expect("Hashes serialization is infallible"),
+                )?;
+                file.sync_all()
+            })
+            .and_then(|()| fs::rename(&temporary, &hashes_file))
+            .map_err(|e| {
+                api::Error::Other(format!(
+                    "Error while saving hashes `{}`: {e}",
+                    hashes_file.display()
+                ))
+            })?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fnv1a_known_answers() {
+        for (content, hash) in [
+            ("", 0xcbf2_9ce4_8422_2325_u64),
+            ("a", 0xaf63_dc4c_8601_ec8c),
+            ("foobar", 0x8594_4171_f739_67e8),
+        ] {
+            assert_eq!(Fnv1aHasher.hash(content), hash.to_be_bytes());
+        }
+    }
+
+    #[test]
+    fn legacy_hashes_are_migrated() {
+        let path = PathBuf::from("/project/.nvim/config.toml");
+        let legacy = HashMap::from([(path.clone(), Sha512Hasher.hash("[set]\nwrap = false"))]);
+        let hashes: Hashes = rmp_serde::from_slice(
+            &rmp_serde::to_vec_named(&legacy).expect("legacy hashes are serialized"),
+        )
+        .expect("legacy hashes are read");
+        let entry = hashes.entry(&path).expect("entry is migrated");
+        assert_eq!(entry.algorithm, HashAlgorithm::Sha512);
+        assert_eq!((entry.approved, entry.snapshot.as_deref()), (None, None));
+        assert!(hashes.is_hashed(&path, "[set]\nwrap = false"));
+        assert!(!hashes.is_hashed(&path, "[set]\nwrap = true"));
+
+        let hashes: Hashes = rmp_serde::from_slice(
+            &rmp_serde::to_vec_named(&hashes).expect("hashes are serialized"),
+        )
+        .expect("versioned hashes are read");
+        assert!(hashes.is_hashed(&path, "[set]\nwrap = false"));
+    }
+
+    #[test]
+    fn trusted_dir_compares_canonical_paths() {
+        let root = std::env::temp_dir().join(format!("config-nvim-trusted-{}", std::process::id()));
+        let trusted = root.join("trusted");
+        let outside = root.join("outside");
+        fs::create_dir_all(trusted.join("sub")).expect("trusted dir is created");
+        fs::create_dir_all(&outside).expect("outside dir is created");
+        fs::write(outside.join("config.toml"), "").expect("config is written");
+
+        let mut hashes = Hashes::default();
+        hashes.trust_dir(trusted.canonicalize().expect("trusted dir exists"));
+        assert!(hashes.trusted_dir(&trusted.join("sub")).is_some());
+        assert!(hashes
+            .trusted_dir(&trusted.join("sub/../../outside/config.toml"))
+            .is_none());
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(&outside, trusted.join("link")).expect("link is created");
+            assert!(hashes
+                .trusted_dir(&trusted.join("link/config.toml"))
+                .is_none());
+        }
+
+        fs::remove_dir_all(root).expect("test dir is removed");
+    }
+
+    #[test]
+    fn hashes_are_stored_by_canonical_path() {
+        let root =
+            std::env::temp_dir().join(format!("config-nvim-canonical-{}", std::process::id()));
+        let config = root.join("dir/config.toml");
+        fs::create_dir_all(root.join("dir")).expect("config dir is created");
+        fs::write(&config, "").expect("config is written");
+
+        let mut hashes = Hashes::default();
+        hashes.add_hash(root.join("dir/../dir/config.toml"), "");
+        assert!(hashes.is_hashed(&config, ""));
+        assert!(hashes
+            .entry(&config.canonicalize().expect("config exists"))
+            .is_some());
+        assert!(hashes.remove_hash(&root.join("./dir/config.toml")));
+        assert!(!hashes.is_hashed(&config, ""));
+
+        fs::remove_dir_all(root).expect("test dir is removed");
+    }
+
+    /// Points `hashes_file` at `hashes` in an empty directory named after `test`.
+    fn temporary_hashes(test: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("config-nvim-{test}-{}", std::process::id()));
+        fs::remove_dir_all(&root).ok();
+        let path = root.join("data/hashes");
+        setup(Object::from(Dictionary::from_iter([(
+            "hashes_file",
+            path.to_string_lossy().into_owned(),
+        )])))
+        .expect("setup is valid");
+        path
+    }
+
+    #[nvim_oxi::test]
+    fn hashes_are_saved_to_the_configured_file() {
+        let path = temporary_hashes("hashes-file");
+        assert_eq!(hashes_file().expect("hashes file is located"), path);
+        Hashes::with(|hashes| {
+            hashes.add_hash(PathBuf::from("/project/.nvim/config.toml"), "");
+            hashes.save()
+        })
+        .expect("hashes are saved");
+        assert!(path.exists());
+
+        Hashes::with(Hashes::reset);
+        Hashes::reload();
+        assert!(Hashes::with(|hashes| {
+            hashes.is_hashed(Path::new("/project/.nvim/config.toml"), "")
+        }));
+    }
+
+    #[nvim_oxi::test]
+    fn corrupt_hashes_are_kept() {
+        let path = temporary_hashes("corrupt-hashes");
+        Hashes::reload();
+        assert_eq!(Hashes::with(|hashes| hashes.paths().count()), 0);
+        assert!(!CORRUPT.with(Cell::get));
+        Hashes::with(|hashes| hashes.save()).expect("missing hashes are created");
+
+        fs::write(&path, "not msgpack").expect("hashes are overwritten");
+        Hashes::reload();
+        assert!(CORRUPT.with(Cell::get));
+        assert!(logged_messages()
+            .iter()
+            .any(|message| message.contains("are corrupt")));
+        assert!(Hashes::with(|hashes| hashes.save()).is_err());
+        assert_eq!(
+            fs::read_to_string(&path).expect("hashes are readable"),
+            "not msgpack"
+        );
+
+        Hashes::with(Hashes::reset);
+        Hashes::with(|hashes| hashes.save()).expect("reset hashes are saved");
+    }
+
+    #[nvim_oxi::test]
+    fn interrupted_saves_keep_the_hashes() {
+        let path = temporary_hashes("interrupted-save");
+        Hashes::with(|hashes| {
+            hashes.add_hash(PathBuf::from("/project/.nvim/config.toml"), "");
+            hashes.save()
+        })
+        .expect("hashes are saved");
+        // a save killed before moving its temporary file over the hashes
+        let temporary = path.with_extension("tmp");
+        fs::write(
+            &temporary,
+            &fs::read(&path).expect("hashes are readable")[..4],
+        )
+        .expect("temporary file is written");
+
+        Hashes::reload();
+        assert!(!CORRUPT.with(Cell::get));
+        assert!(Hashes::with(|hashes| {
+            hashes.is_hashed(Path::new("/project/.nvim/config.toml"), "")
+        }));
+        Hashes::with(|hashes| hashes.save()).expect("hashes are saved again");
+        assert!(!temporary.exists());
+        assert!(Hashes::load().expect("hashes are valid").is_some());
+    }
+
+    #[nvim_oxi::test]
+    fn reload_follows_the_hashes_file() {
+        let path = temporary_hashes("reload-hashes");
+        Hashes::with(|hashes| {
+            hashes.add_hash(PathBuf::from("/first/.nvim/config.toml"), "");
+            hashes.save()
+        })
+        .expect("hashes are saved");
+
+        // e.g. another Neovim instance trusting a different config
+        let mut changed = Hashes::default();
+        changed.add_hash(PathBuf::from("/second/.nvim/config.toml"), "");
+        fs::write(
+            &path,
+            rmp_serde::to_vec_named(&changed).expect("hashes are serialized"),
+        )
+        .expect("hashes are changed");
+        assert!(Hashes::with(|hashes| {
+            hashes.is_hashed(Path::new("/first/.nvim/config.toml"), "")
+        }));
+
+        Hashes::reload();
+        Hashes::with(|hashes| {
+            assert!(!hashes.is_hashed(Path::new("/first/.nvim/config.toml"), ""));
+            assert!(hashes.is_hashed(Path::new("/second/.nvim/config.toml"), ""));
+        });
+    }
+}
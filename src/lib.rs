@@ -4,69 +4,166 @@
     clippy::too_many_lines,
     clippy::unnecessary_wraps,
     clippy::wildcard_imports,
-    clippy::module_name_repetitions
+    clippy::module_name_repetitions,
+    clippy::missing_errors_doc,
+    clippy::missing_panics_doc,
+    clippy::must_use_candidate,
+    clippy::return_self_not_must_use
 )]
 #![warn(clippy::unwrap_used)]
 use std::{
     collections::HashMap,
-    env, fs,
+    env, io,
     path::{Path, PathBuf},
+    rc::Rc,
 };
 
+use glob::Pattern;
 use itertools::{Either, Itertools};
 pub use nvim_oxi as oxi;
 pub use oxi::{api, api::Error as ApiError, Error};
 use oxi::{
-    api::{opts::*, types::LogLevel},
-    conversion, Dictionary, Function,
+    api::{
+        opts::*,
+        types::{AutocmdCallbackArgs, CommandArgs, CommandComplete, CommandNArgs, LogLevel},
+        Buffer,
+    },
+    conversion, Array, Dictionary, Function, Object,
 };
 use walkdir::WalkDir;
 
 #[macro_use]
 mod macros;
 
-mod config;
+pub mod config;
 use config::*;
 
 mod hashes;
 use hashes::*;
 
+mod setup;
+use setup::*;
+
+mod termcodes;
+use termcodes::*;
+
+mod log;
+use log::*;
+
 type Result<T = (), E = oxi::Error> = std::result::Result<T, E>;
 type ApiResult<T = ()> = Result<T, ApiError>;
 type ConvResult<T = ()> = Result<T, conversion::Error>;
 
-fn config_files(path: &Path) -> impl Iterator<Item = (PathBuf, String, Config)> {
+/// Files in `path` matching `patterns`, sorted by name so merging does not depend on the order
+/// the filesystem lists them in.
+fn config_files<'a>(path: &Path, patterns: &'a [Pattern]) -> impl Iterator<Item = PathBuf> + 'a {
     WalkDir::new(path)
+        .sort_by_file_name()
         .into_iter()
-        .filter_map(|path| match path {
-            Ok(path) if path.file_type().is_file() => match path.path().extension()?.to_str()? {
-                "toml" => {
-                    let string = fs::read_to_string(path.path()).ok()?;
-                    let config = toml::from_str(&string).ok()?;
-                    Some((path.into_path(), string, config))
-                }
-                "yaml" | "yml" | "json" => {
-                    let string = fs::read_to_string(path.path()).ok()?;
-                    let config = serde_yaml::from_str(&string).ok()?;
-                    Some((path.into_path(), string, config))
-                }
-                _ => None,
-            },
+        .filter_map(move |path| match path {
+            Ok(path)
+                if path.file_type().is_file()
+                    && patterns
+                        .iter()
+                        .any(|pattern| pattern.matches(&path.file_name().to_string_lossy())) =>
+            {
+                Some(path.into_path())
+            }
             _ => None,
         })
 }
 
-fn get_config_dirs() -> Vec<PathBuf> {
+/// Sorts `configs` into the order they are merged in: by `priority`, then the ones on the
+/// runtime path before local ones, then by path.
+fn sort_configs(configs: &mut [(PathBuf, Config)], runtime_files: &[PathBuf]) {
+    configs.sort_by(|(path, config), (other_path, other)| {
+        (config.priority, !runtime_files.contains(path), path).cmp(&(
+            other.priority,
+            !runtime_files.contains(other_path),
+            other_path,
+        ))
+    });
+}
+
+/// The file last defining each option and mapping of `configs`, which are in merge order.
+fn config_sources(configs: &[(PathBuf, Config)]) -> HashMap<String, PathBuf> {
+    // later configs override the options and mappings of earlier ones
+    let mut sources = HashMap::new();
+    for (path, config) in configs {
+        for name in config.defined_names() {
+            sources.insert(name, path.clone());
+        }
+    }
+    sources
+}
+
+/// Parses all `files` up front, reporting every invalid one in a single notification.
+fn parse_configs(files: Vec<PathBuf>) -> Result<Vec<(PathBuf, String, Config)>> {
+    let (errors, configs): (Vec<_>, Vec<_>) =
+        files
+            .into_iter()
+            .partition_map(|path| match Config::load(&path) {
+                Ok((config, source)) => Either::Right((path, source, config)),
+                Err(error) => Either::Left(error),
+            });
+    for error in &errors {
+        record_log(error);
+    }
+    if !errors.is_empty() {
+        api::notify(
+            &format!(
+                "Found {} invalid config file(s):\n  {}",
+                errors.len(),
+                errors.join("\n  ")
+            ),
+            LogLevel::Error,
+            &NotifyOpts::default(),
+        )?;
+    }
+    Ok(configs)
+}
+
+/// Config files on the runtime path and local config files, in the order they are applied.
+fn discover_config_files(setup: &Setup) -> Result<(Vec<PathBuf>, Vec<PathBuf>)> {
+    let patterns: Vec<_> = setup
+        .patterns
+        .iter()
+        .filter_map(|pattern| {
+            Pattern::new(pattern)
+                .map_err(|error| {
+                    log_error!("Invalid config file pattern `{pattern}`: {error}");
+                })
+                .ok()
+        })
+        .collect();
+    let mut runtime_files = Vec::new();
+    for pattern in &setup.patterns {
+        runtime_files.extend(api::get_runtime_file(&format!("config/{pattern}"), true)?);
+    }
+    let local_files = setup
+        .extra_dirs
+        .iter()
+        .map(|dir| {
+            let dir = PathBuf::from(expand_env(dir));
+            dir.canonicalize().unwrap_or(dir)
+        })
+        .chain(get_config_dirs(&setup.local_dir))
+        .flat_map(|path| config_files(&path, &patterns))
+        .collect();
+    Ok((runtime_files, local_files))
+}
+
+fn get_config_dirs(dir_name: &str) -> Vec<PathBuf> {
     let mut nvim_folders = Vec::new();
     let Ok(cwd) = env::current_dir() else { return Vec::new() };
     let mut cwd = cwd.as_path();
-    let nvim_dir = cwd.join(".nvim/config");
+    let nvim_dir = cwd.join(dir_name);
     if nvim_dir.is_dir() {
         nvim_folders.push(nvim_dir);
     }
     while let Some(parent) = cwd.parent() {
         cwd = parent;
-        let nvim_dir = cwd.join(".nvim/config");
+        let nvim_dir = cwd.join(dir_name);
         if nvim_dir.is_dir() {
             nvim_folders.push(nvim_dir);
         }
@@ -75,40 +172,237 @@ fn get_config_dirs() -> Vec<PathBuf> {
     nvim_folders
 }
 
-fn load_config(_: ()) -> Result<()> {
-    let get_files = |pattern: &'static str| {
-        // api::call_function("globpath", (rtp, pattern))
-        api::get_runtime_file(pattern, true)
+fn revoke_config(args: CommandArgs) -> Result<()> {
+    Hashes::with(|hashes| {
+        if args.fargs.is_empty() {
+            hashes.reset();
+        } else {
+            for path in &args.fargs {
+                // deleted configs can still be revoked by the path they were trusted under
+                let path = resolve_path(path).unwrap_or_else(|_| {
+                    env::current_dir().map_or_else(|_| PathBuf::from(path), |cwd| cwd.join(path))
+                });
+                if !hashes.remove_hash(&path) {
+                    log_error!("`{}` is not a trusted config", path.display());
+                }
+            }
+        }
+        hashes.save()
+    })?;
+    Ok(())
+}
+
+fn trust_dir(args: CommandArgs) -> Result<()> {
+    let Some(dir) = args.fargs.first() else {
+        return Ok(());
     };
+    let dir = match resolve_path(dir) {
+        Ok(dir) => dir,
+        Err(error) => {
+            log_error!("Unable to trust `{dir}`: {error}");
+            return Ok(());
+        }
+    };
+    Hashes::with(|hashes| {
+        hashes.trust_dir(dir.clone());
+        hashes.save()
+    })?;
+    api::notify(
+        &format!(
+            "Trusting every config under `{}`, changed and new configs there are applied without \
+             review. Run :ConfigRevoke {0} to undo",
+            dir.display()
+        ),
+        LogLevel::Warn,
+        &NotifyOpts::default(),
+    )?;
+    load_config(())?;
+    Ok(())
+}
 
-    let mut conditional_configs: HashMap<Condition, Config> = HashMap::new();
+fn rehash_config(_: CommandArgs) -> Result<()> {
+    Hashes::reload();
+    let trusted = Hashes::with(|hashes| hashes.paths().count());
+    api::notify(
+        &format!("Reloaded trust state, {trusted} local config(s) are trusted"),
+        LogLevel::Info,
+        &NotifyOpts::default(),
+    )?;
+    Ok(())
+}
+
+fn undo_config(_: CommandArgs) -> Result<()> {
+    let restored = undo_snapshot()?;
+    api::notify(
+        &format!("Restored {restored} option(s) to before the last apply"),
+        LogLevel::Info,
+        &NotifyOpts::default(),
+    )?;
+    Ok(())
+}
+
+fn complete_trusted((arg_lead, _, _): (String, String, usize)) -> Result<Vec<String>> {
+    Ok(Hashes::with(|hashes| {
+        hashes
+            .paths()
+            .chain(hashes.trusted_dirs())
+            .map(|path| path.to_string_lossy().into_owned())
+            .filter(|path| path.starts_with(&arg_lead))
+            .sorted()
+            .collect()
+    }))
+}
+
+fn check_config(_: CommandArgs) -> Result<()> {
+    let (runtime_files, local_files) = discover_config_files(&Setup::get())?;
+    let files = runtime_files.into_iter().chain(local_files).collect_vec();
+    let mut problems = Vec::new();
+    for file in &files {
+        match Config::load(file) {
+            Ok((config, _)) => problems.extend(
+                config
+                    .validate()
+                    .into_iter()
+                    .map(|problem| format!("{}: {problem}", file.display())),
+            ),
+            Err(error) => problems.push(error),
+        }
+    }
+    if problems.is_empty() {
+        api::notify(
+            &format!("All {} config file(s) are valid", files.len()),
+            LogLevel::Info,
+            &NotifyOpts::default(),
+        )?;
+    } else {
+        api::notify(
+            &format!(
+                "Found {} problem(s) in config files:\n  {}",
+                problems.len(),
+                problems.join("\n  ")
+            ),
+            LogLevel::Warn,
+            &NotifyOpts::default(),
+        )?;
+    }
+    Ok(())
+}
+
+/// Loads and applies all configs, returning how many were loaded, the paths of the ones that
+/// still need to be allowed and the errors reported while loading.
+fn load_config(_: ()) -> Result<Dictionary> {
+    snapshot(|| load_configs(None))
+}
+
+/// Loads and applies all configs, trusting the config at the path with the given content as well.
+fn load_configs(trusting: Option<(&Path, &str)>) -> Result<Dictionary> {
+    clear_log();
+    // plugins loaded since the last load could have added options
+    OptionInfoCache::clear();
+    let setup = Setup::get();
+    let (runtime_files, local_files) = discover_config_files(&setup)?;
+    let parsed = parse_configs(runtime_files.iter().cloned().chain(local_files).collect())?;
 
-    for path in get_files("config/*.yml")?
-        .chain(get_files("config/*.yaml")?)
-        .chain(get_files("config/*.json")?)
-        .chain(get_files("config/*.toml")?)
-    {
-        continue_on_error!(Config::load(path.as_path()), error, "{error}")
-            .0
-            .merge_into_hashmap(&mut conditional_configs);
+    Hashes::reload();
+    if let Some((path, source)) = trusting {
+        Hashes::with(|hashes| hashes.add_hash(path.to_owned(), source));
     }
 
-    let mut hashes = Hashes::load().unwrap_or_default();
+    let listing: Vec<_> = Hashes::with(|hashes| {
+        parsed
+            .iter()
+            .map(|(path, source, config)| {
+                let status = if !config.is_enabled() {
+                    "disabled"
+                } else if runtime_files.contains(path) {
+                    "runtime path"
+                } else if hashes.is_hashed(path, source) {
+                    "trusted"
+                } else {
+                    "untrusted"
+                };
+                let summary = config.summary().replace('\n', "\n  ");
+                format!("{} ({status})\n  {summary}", path.display())
+            })
+            .collect()
+    });
 
-    let config_files: Vec<_> = get_config_dirs()
+    let parsed = parsed
+        .into_iter()
+        .filter(|(_, _, config)| config.is_enabled())
+        .collect_vec();
+
+    let watched: Vec<PathBuf> = Hashes::with(|hashes| {
+        parsed
+            .iter()
+            .filter(|(path, source, _)| {
+                runtime_files.contains(path) || hashes.is_hashed(path, source)
+            })
+            .map(|(path, ..)| path.clone())
+            .collect()
+    });
+
+    let (mut configs, local_configs): (Vec<_>, Vec<_>) =
+        parsed.into_iter().partition_map(|(path, source, config)| {
+            if runtime_files.contains(&path) {
+                Either::Left((path, config))
+            } else {
+                Either::Right((path, source, config))
+            }
+        });
+
+    let (unknown, known) = Hashes::with(|hashes| hashes.unhashed(local_configs));
+    configs.extend(known);
+    sort_configs(&mut configs, &runtime_files);
+    let loaded = configs.len();
+    let sources = config_sources(&configs);
+    let loaded_files = configs.iter().map(|(path, _)| path.clone()).collect_vec();
+    let configs = configs.into_iter().map(|(_, config)| config).collect_vec();
+    let unknown_paths: Array = unknown
         .iter()
-        .flat_map(|path| config_files(path))
+        .map(|path| path.to_string_lossy().into_owned())
         .collect();
-    let (unknown, known) = hashes.unhashed(config_files);
-    for config in known {
-        config.merge_into_hashmap(&mut conditional_configs);
+
+    if setup.warn_condition_conflicts {
+        for (config, other) in configs.iter().tuple_combinations() {
+            for conflict in config.conflicts(other) {
+                api::notify(&conflict, LogLevel::Warn, &NotifyOpts::default())?;
+            }
+        }
+    }
+
+    let mut conditional_configs = Config::merge_all(configs);
+    if setup.warn_keymap_conflicts || setup.strict_sets {
+        for (condition, config) in &conditional_configs {
+            let keymap_conflicts = setup
+                .warn_keymap_conflicts
+                .then(|| config.keymap_conflicts())
+                .unwrap_or_default();
+            let assign_conflicts = setup
+                .strict_sets
+                .then(|| config.assign_conflicts())
+                .unwrap_or_default();
+            for conflict in keymap_conflicts.into_iter().chain(assign_conflicts) {
+                let condition = if *condition == Condition::default() {
+                    String::new()
+                } else {
+                    format!(" when {condition}")
+                };
+                api::notify(
+                    &format!("{conflict}{condition}"),
+                    LogLevel::Warn,
+                    &NotifyOpts::default(),
+                )?;
+            }
+        }
     }
     if !unknown.is_empty() {
         {
             let unknown: Vec<_> = unknown.iter().map(|p| p.to_string_lossy()).collect();
             api::notify(
                 &format!(
-                    "Found new local config{}: \n  {}\nRun :ConfigAllow to activate",
+                    "Found new local config{}: \n  {}\nRun :ConfigAllow to activate or \
+                     :ConfigReview to inspect",
                     (unknown.len() > 1).then_some("s").unwrap_or_default(),
                     unknown.join("\n  ")
                 ),
@@ -116,44 +410,547 @@ fn load_config(_: ()) -> Result<()> {
                 &NotifyOpts::default(),
             )?;
         }
+        let review = unknown.clone();
         api::create_user_command(
-            "ConfigAllow",
+            "ConfigReview",
             move |_| {
-                for file in &unknown {
-                    let (config, source) = continue_on_error!(Config::load(file), error, "{error}");
-                    config.apply(false)?;
-                    hashes.add_hash(file.clone(), &source);
+                for file in &review {
+                    let (config, _) = continue_on_error!(Config::load(file), error, "{error}");
+                    let approved =
+                        Hashes::with(|hashes| hashes.entry(file).and_then(|entry| entry.approved));
+                    let approved = match approved {
+                        Some(approved) => format!(
+                            "\npreviously approved different content on {}",
+                            api::call_function::<_, String>("strftime", ("%c", approved))?
+                        ),
+                        None => String::new(),
+                    };
+                    api::notify(
+                        &format!("{}:\n{}{approved}", file.display(), config.summary()),
+                        LogLevel::Info,
+                        &NotifyOpts::default(),
+                    )?;
                 }
-                hashes.save()?;
+                Ok(())
+            },
+            &CreateCommandOpts::default(),
+        )?;
+        api::create_user_command(
+            "ConfigAllow",
+            move |_| {
+                let allowed = snapshot(|| {
+                    let mut allowed = Vec::new();
+                    for file in &unknown {
+                        let (config, source) =
+                            continue_on_error!(Config::load(file), error, "{error}");
+                        config.apply(false)?;
+                        allowed.push((file.clone(), source));
+                    }
+                    Ok::<_, ApiError>(allowed)
+                })?;
+                Hashes::with(|hashes| {
+                    for (file, source) in allowed {
+                        hashes.add_hash(file, &source);
+                    }
+                    hashes.save()
+                })?;
                 Ok(())
             },
             &CreateCommandOpts::default(),
         )?;
     }
 
-    if let Some(config) = conditional_configs.remove(&Condition::default()) {
-        config.apply(false)?;
-    }
-    // for (condition, config) in conditional_configs {
-    //     api::create_autocmd(
-    //         condition.events().iter().map(AsRef::as_ref),
-    //         &condition
-    //             .opts()
-    //             .callback(move |_| -> Result<bool> {
-    //                 config.apply(true)?;
-    //                 Ok(false)
-    //             })
-    //             .build(),
-    //     )
-    //     .expect("Create autocommand for conditional config");
-    // }
+    api::create_user_command(
+        "ConfigRevoke",
+        revoke_config,
+        &CreateCommandOpts::builder()
+            .nargs(CommandNArgs::Any)
+            .complete(CommandComplete::CustomList(Function::from_fn(
+                complete_trusted,
+            )))
+            .desc("Revoke trust of local configs (all if no path is given)")
+            .build(),
+    )?;
+
+    api::create_user_command(
+        "ConfigTrust",
+        trust_dir,
+        &CreateCommandOpts::builder()
+            .nargs(CommandNArgs::One)
+            .complete(CommandComplete::Dir)
+            .desc("Trust all local configs in a directory, including future changes")
+            .build(),
+    )?;
+
+    api::create_user_command(
+        "ConfigRehash",
+        rehash_config,
+        &CreateCommandOpts::builder()
+            .desc("Reload the trust state of local configs from disk")
+            .build(),
+    )?;
+
+    api::create_user_command(
+        "ConfigList",
+        move |_| {
+            api::notify(
+                &format!("Loaded config files:\n{}", listing.join("\n")),
+                LogLevel::Info,
+                &NotifyOpts::default(),
+            )?;
+            Ok(())
+        },
+        &CreateCommandOpts::builder()
+            .desc("List the loaded config files with a summary of their contents")
+            .build(),
+    )?;
+
+    let names = sources.keys().cloned().sorted().collect_vec();
+    api::create_user_command(
+        "ConfigEdit",
+        move |args: CommandArgs| {
+            let Some(name) = args.fargs.first() else {
+                let files: Array = loaded_files
+                    .iter()
+                    .map(|path| {
+                        Dictionary::from_iter([("filename", path.to_string_lossy().into_owned())])
+                    })
+                    .collect();
+                api::call_function::<_, i64>("setqflist", (files,))?;
+                api::command("copen")?;
+                return Ok(());
+            };
+            let Some(path) = sources.get(name) else {
+                log_error!("`{name}` is not set or mapped by a loaded config");
+                return Ok(());
+            };
+            let path: String =
+                api::call_function("fnameescape", (path.to_string_lossy().into_owned(),))?;
+            api::command(&format!("edit {path}"))?;
+            Ok(())
+        },
+        &CreateCommandOpts::builder()
+            .nargs(CommandNArgs::ZeroOrOne)
+            .complete(CommandComplete::CustomList(Function::from_fn(
+                move |(arg_lead, _, _): (String, String, usize)| -> Result<Vec<String>> {
+                    Ok(names
+                        .iter()
+                        .filter(|name| name.starts_with(&arg_lead))
+                        .cloned()
+                        .collect())
+                },
+            )))
+            .desc("Edit the config file setting an option or mapping a lhs, list all if none given")
+            .build(),
+    )?;
+
+    api::create_user_command(
+        "ConfigLog",
+        open_log,
+        &CreateCommandOpts::builder()
+            .desc("Show the errors reported since configs were last loaded")
+            .build(),
+    )?;
+
+    api::create_user_command(
+        "ConfigCheck",
+        check_config,
+        &CreateCommandOpts::builder()
+            .desc("Validate all config files without applying them")
+            .build(),
+    )?;
+
+    api::create_user_command(
+        "ConfigUndo",
+        undo_config,
+        &CreateCommandOpts::builder()
+            .desc("Restore the options changed by the last apply")
+            .build(),
+    )?;
+
+    let group = api::create_augroup("Config", &CreateAugroupOpts::builder().clear(true).build())?;
+
+    let config = Rc::new(
+        conditional_configs
+            .remove(&Condition::default())
+            .unwrap_or_default(),
+    );
+    config.apply(false)?;
+    if setup.reapply_highlights && !config.highlight.is_empty() {
+        let config = config.clone();
+        api::create_autocmd(
+            ["ColorScheme"],
+            &CreateAutocmdOpts::builder()
+                .group(group)
+                .callback(move |_: AutocmdCallbackArgs| -> Result<bool> {
+                    config.apply_highlights()?;
+                    Ok(false)
+                })
+                .build(),
+        )?;
+    }
+
+    api::create_user_command(
+        "ConfigApply",
+        move |args: CommandArgs| {
+            let sections = match args.fargs.first() {
+                None => Section::ALL.to_vec(),
+                Some(name) => match Section::from_name(name) {
+                    Some(section) => vec![section],
+                    None => {
+                        log_error!("Unknown config section `{name}`");
+                        return Ok(());
+                    }
+                },
+            };
+            for section in sections {
+                config.apply_section(section, false)?;
+            }
+            Ok(())
+        },
+        &CreateCommandOpts::builder()
+            .nargs(CommandNArgs::ZeroOrOne)
+            .complete(CommandComplete::CustomList(Function::from_fn(
+                |(arg_lead, _, _): (String, String, usize)| -> Result<Vec<String>> {
+                    Ok(Section::ALL
+                        .into_iter()
+                        .map(Section::name)
+                        .filter(|name| name.starts_with(&arg_lead))
+                        .map(ToOwned::to_owned)
+                        .collect())
+                },
+            )))
+            .desc("Apply a section of the unconditional configs again, all if none is given")
+            .build(),
+    )?;
+
+    // deferred configs would never be applied when loading after startup
+    let started = api::get_vvar::<i64>("vim_did_enter")? == 1;
+    for (condition, config) in conditional_configs {
+        let buffer_local = condition.is_buffer_local();
+        let deferred = condition.is_deferred() && !started;
+        let config = Rc::new(RevertingConfig::new(config, setup.revert_conditional));
+        if !deferred && !buffer_local && condition.matches(&Buffer::current())? {
+            config.apply(false)?;
+        }
+        if !deferred && buffer_local && setup.apply_to_open_buffers {
+            for buffer in api::list_bufs() {
+                if buffer.is_loaded() && condition.matches(&buffer)? {
+                    let config = config.clone();
+                    buffer.call(move |_| Ok(config.apply(true)?))?;
+                }
+            }
+        }
+        let mut autocmds = condition.autocmds();
+        if setup.revert_conditional && buffer_local {
+            // reapply what was reverted when leaving a matching buffer
+            autocmds.push(("BufEnter", vec!["*".to_string()]));
+            let condition = condition.clone();
+            let config = config.clone();
+            api::create_autocmd(
+                ["BufLeave"],
+                &CreateAutocmdOpts::builder()
+                    .group(group)
+                    .callback(move |args: AutocmdCallbackArgs| -> Result<bool> {
+                        if condition.matches(&args.buffer)? {
+                            config.restore()?;
+                        }
+                        Ok(false)
+                    })
+                    .build(),
+            )?;
+        }
+        for (event, patterns) in autocmds {
+            let condition = condition.clone();
+            let config = config.clone();
+            api::create_autocmd(
+                [event],
+                &CreateAutocmdOpts::builder()
+                    .group(group)
+                    .patterns(patterns.iter().map(AsRef::as_ref))
+                    .callback(move |args: AutocmdCallbackArgs| -> Result<bool> {
+                        if condition.matches(&args.buffer)? {
+                            if buffer_local {
+                                let config = config.clone();
+                                let buffer = args.buffer;
+                                let apply = move |()| -> Result<()> {
+                                    // buffer-local keys and options target the buffer of the event
+                                    if buffer.is_valid() {
+                                        buffer.call(move |_| Ok(config.apply(true)?))?;
+                                    }
+                                    Ok(())
+                                };
+                                if condition.is_after_ftplugin() {
+                                    // ftplugins are sourced by `FileType` autocommands that can
+                                    // run after this one
+                                    oxi::schedule(apply);
+                                } else {
+                                    apply(())?;
+                                }
+                            } else {
+                                config.apply(false)?;
+                            }
+                        } else if !buffer_local {
+                            config.restore()?;
+                        }
+                        Ok(false)
+                    })
+                    .build(),
+            )?;
+        }
+    }
+
+    if setup.auto_reload {
+        for path in watched {
+            api::create_autocmd(
+                ["BufWritePost"],
+                &CreateAutocmdOpts::builder()
+                    .group(group)
+                    .patterns([path.to_string_lossy().as_ref()])
+                    .callback(move |_: AutocmdCallbackArgs| -> Result<bool> {
+                        reload_config(&path)?;
+                        Ok(false)
+                    })
+                    .build(),
+            )?;
+        }
+    }
+    Ok(Dictionary::from_iter([
+        (
+            "loaded",
+            Object::from(i64::try_from(loaded).unwrap_or(i64::MAX)),
+        ),
+        ("unknown", Object::from(unknown_paths)),
+        (
+            "errors",
+            Object::from(logged_messages().into_iter().collect::<Array>()),
+        ),
+    ]))
+}
+
+/// Resolves `path` given from Lua or a command against the cwd, canonicalizing it so `..` and
+/// symlinks can't make it differ from the paths configs are found under.
+fn resolve_path(path: &str) -> io::Result<PathBuf> {
+    env::current_dir()?.join(path).canonicalize()
+}
+
+/// Allows the local config at `path` with its current content, like `:ConfigAllow` does.
+fn trust_config(path: String) -> Result<()> {
+    let path = resolve_path(&path)
+        .map_err(|error| ApiError::Other(format!("Unable to resolve `{path}`: {error}")))?;
+    let (_, source) = Config::load(&path).map_err(ApiError::Other)?;
+    Hashes::reload();
+    Hashes::with(|hashes| {
+        hashes.add_hash(path, &source);
+        hashes.save()
+    })?;
+    Ok(())
+}
+
+/// Whether the local config at `path` is allowed with its current content.
+fn is_trusted(path: String) -> Result<bool> {
+    let Ok(path) = resolve_path(&path) else {
+        return Ok(false);
+    };
+    let Ok((_, source)) = Config::load(&path) else {
+        return Ok(false);
+    };
+    Hashes::reload();
+    Ok(Hashes::with(|hashes| hashes.is_hashed(&path, &source)))
+}
+
+/// Applies a config given as a Lua table, ignoring its conditions.
+fn apply_config(object: Object) -> Result<()> {
+    let config = Config::from_object(object).map_err(ApiError::from)?;
+    snapshot(|| config.apply(false))?;
+    Ok(())
+}
+
+/// Loads all configs again after the config at `path` was changed, trusting its new content if
+/// it was trusted before and applies without errors.
+fn reload_config(path: &Path) -> Result<()> {
+    let source = match Config::load(path) {
+        Ok((_, source)) => source,
+        Err(error) => {
+            log_error!("{error}");
+            return Ok(());
+        }
+    };
+    Hashes::reload();
+    let trusted = Hashes::with(|hashes| hashes.entry(path).is_some());
+    let loaded = snapshot(|| load_configs(trusted.then_some((path, source.as_str()))));
+    if trusted {
+        if loaded.is_ok() && logged_messages().is_empty() {
+            Hashes::with(|hashes| hashes.save())?;
+        } else {
+            // drops the hash of the new content
+            Hashes::reload();
+            api::notify(
+                &format!(
+                    "Not trusting the changes to {} as they were applied with errors",
+                    path.display()
+                ),
+                LogLevel::Warn,
+                &NotifyOpts::default(),
+            )?;
+        }
+    }
+    loaded?;
     Ok(())
 }
 
 #[oxi::module]
 fn config() -> Result<Dictionary, nvim_oxi::Error> {
-    Ok(Dictionary::from_iter([(
-        "load_config",
-        Function::from_fn(load_config),
-    )]))
+    Ok(Dictionary::from_iter([
+        ("load_config", Object::from(Function::from_fn(load_config))),
+        ("apply", Object::from(Function::from_fn(apply_config))),
+        ("trust", Object::from(Function::from_fn(trust_config))),
+        ("is_trusted", Object::from(Function::from_fn(is_trusted))),
+        ("setup", Object::from(Function::from_fn(setup))),
+    ]))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn configs_are_sorted_by_priority_origin_and_path() {
+        let config = |priority| Config {
+            priority,
+            ..Config::default()
+        };
+        let runtime = PathBuf::from("/home/user/.config/nvim/config/b.toml");
+        let mut configs = vec![
+            (PathBuf::from("/project/.nvim/config/b.toml"), config(0)),
+            (PathBuf::from("/project/.nvim/config/a.toml"), config(0)),
+            (PathBuf::from("/project/.nvim/config/z.toml"), config(-1)),
+            (runtime.clone(), config(0)),
+            (
+                PathBuf::from("/home/user/.config/nvim/config/a.toml"),
+                config(10),
+            ),
+        ];
+        sort_configs(&mut configs, &[runtime]);
+        assert_eq!(
+            configs
+                .iter()
+                .map(|(path, _)| path.to_str().expect("path is utf-8"))
+                .collect_vec(),
+            [
+                "/project/.nvim/config/z.toml",
+                "/home/user/.config/nvim/config/b.toml",
+                "/project/.nvim/config/a.toml",
+                "/project/.nvim/config/b.toml",
+                "/home/user/.config/nvim/config/a.toml",
+            ]
+        );
+    }
+
+    #[test]
+    fn sources_are_the_last_defining_config() {
+        let config = |source| Config::from_str(source, Format::Toml).expect("config is valid");
+        let sources = config_sources(&[
+            (
+                PathBuf::from("user.toml"),
+                config("[set]\nnumber = true\nwrap = false\n[keys]\nmodes = \"n\"\nQ = \"gq\""),
+            ),
+            (
+                PathBuf::from("project.toml"),
+                config("[set]\nwrap = true\n[filetype.rust.set]\ntextwidth = 100"),
+            ),
+        ]);
+        assert_eq!(
+            sources
+                .iter()
+                .map(|(name, path)| (name.as_str(), path.to_str().expect("path is utf-8")))
+                .sorted()
+                .collect_vec(),
+            [
+                ("Q", "user.toml"),
+                ("number", "user.toml"),
+                ("textwidth", "project.toml"),
+                ("wrap", "project.toml"),
+            ]
+        );
+    }
+
+    #[test]
+    fn config_files_are_found_sorted_by_name() {
+        let root = env::temp_dir().join(format!("config-nvim-files-{}", std::process::id()));
+        fs::create_dir_all(root.join("sub")).expect("config dir is created");
+        for file in ["c.toml", "a.yaml", "sub/b.json", "b.toml", "notes.txt"] {
+            fs::write(root.join(file), "").expect("config is written");
+        }
+        let patterns = ["*.toml", "*.yaml", "*.json"]
+            .map(|pattern| Pattern::new(pattern).expect("pattern is valid"));
+        let files = config_files(&root, &patterns)
+            .map(|path| {
+                path.strip_prefix(&root)
+                    .expect("file is in the config dir")
+                    .to_owned()
+            })
+            .collect_vec();
+        assert_eq!(
+            files,
+            ["a.yaml", "b.toml", "c.toml", "sub/b.json"].map(PathBuf::from)
+        );
+        fs::remove_dir_all(root).expect("test dir is removed");
+    }
+
+    #[nvim_oxi::test]
+    fn invalid_configs_are_reported_together() {
+        let root = env::temp_dir().join(format!("config-nvim-invalid-{}", std::process::id()));
+        fs::create_dir_all(&root).expect("config dir is created");
+        fs::write(root.join("valid.toml"), "[set]\nnumber = true").expect("config is written");
+        fs::write(root.join("broken.toml"), "[set\nnumber = true").expect("config is written");
+        fs::write(root.join("broken.yaml"), "set: [").expect("config is written");
+        let configs = parse_configs(
+            ["broken.toml", "valid.toml", "broken.yaml"]
+                .map(|file| root.join(file))
+                .into(),
+        )
+        .expect("errors are reported");
+        fs::remove_dir_all(&root).expect("test dir is removed");
+
+        assert_eq!(
+            configs.iter().map(|(path, ..)| path.clone()).collect_vec(),
+            [root.join("valid.toml")]
+        );
+        let errors = logged_messages();
+        assert_eq!(errors.len(), 2);
+        for file in ["broken.toml", "broken.yaml"] {
+            assert!(errors.iter().any(|error| error.contains(file)));
+        }
+    }
+
+    #[nvim_oxi::test]
+    fn deferred_configs_are_applied_on_their_event() {
+        let rtp = env::temp_dir().join(format!("config-nvim-deferred-{}", std::process::id()));
+        fs::create_dir_all(rtp.join("config")).expect("config dir is created");
+        fs::write(
+            rtp.join("config/deferred.toml"),
+            "[[conditions]]\non = \"UIEnter\"\n[set]\ntextwidth = 42",
+        )
+        .expect("config is written");
+        api::command(&format!("set runtimepath^={}", rtp.display())).expect("rtp is extended");
+        load_configs(None).expect("configs are loaded");
+        fs::remove_dir_all(&rtp).expect("test dir is removed");
+
+        let autocmds = api::get_autocmds(
+            &GetAutocmdsOpts::builder()
+                .group("Config")
+                .events(["UIEnter"])
+                .build(),
+        )
+        .expect("autocommands are listed")
+        .count();
+        assert_eq!(autocmds, 1);
+        api::exec_autocmds(["UIEnter"], &ExecAutocmdsOpts::default()).expect("event is fired");
+        let textwidth: i64 = api::get_option_value("textwidth", &OptionValueOpts::default())
+            .expect("textwidth is readable");
+        assert_eq!(textwidth, 42);
+    }
 }
@@ -0,0 +1,56 @@
+use std::{
+    cell::RefCell,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::*;
+
+thread_local! {
+    /// Messages reported since the last `load_config`, with the seconds since the unix epoch
+    /// they were reported at.
+    static LOG: RefCell<Vec<(i64, String)>> = RefCell::default();
+}
+
+pub fn record_log(message: &str) {
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .ok()
+        .and_then(|duration| i64::try_from(duration.as_secs()).ok())
+        .unwrap_or_default();
+    LOG.with(|log| log.borrow_mut().push((time, message.to_owned())));
+}
+
+/// The recorded messages without their time.
+pub fn logged_messages() -> Vec<String> {
+    LOG.with(|log| {
+        log.borrow()
+            .iter()
+            .map(|(_, message)| message.clone())
+            .collect()
+    })
+}
+
+pub fn clear_log() {
+    LOG.with(|log| log.borrow_mut().clear());
+}
+
+/// Opens the recorded messages in a scratch buffer.
+pub fn open_log(_: CommandArgs) -> Result<()> {
+    let mut lines = Vec::new();
+    for (time, message) in LOG.with(|log| log.borrow().clone()) {
+        let time: String = api::call_function("strftime", ("%X", time))?;
+        let mut message = message.lines();
+        lines.push(format!("[{time}] {}", message.next().unwrap_or_default()));
+        lines.extend(message.map(|line| format!("  {line}")));
+    }
+    if lines.is_empty() {
+        lines.push("No messages since the last load".to_owned());
+    }
+    api::command("new")?;
+    let local = OptionValueOpts::builder().scope(OptionScope::Local).build();
+    api::set_option_value("buftype", "nofile", &local)?;
+    api::set_option_value("bufhidden", "wipe", &local)?;
+    Buffer::current().set_lines(.., false, lines)?;
+    api::set_option_value("modifiable", false, &local)?;
+    Ok(())
+}
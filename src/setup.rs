@@ -0,0 +1,73 @@
+use std::cell::RefCell;
+
+use oxi::{Object, ObjectKind};
+use serde::Deserialize;
+use smart_default::SmartDefault;
+
+use crate::*;
+
+#[derive(Debug, Deserialize, SmartDefault, Clone)]
+#[serde(default)]
+pub struct Setup {
+    /// Apply conditional configs to already open buffers matching them on `load_config`.
+    pub apply_to_open_buffers: bool,
+    /// Algorithm used to hash newly allowed local configs.
+    pub hash_algorithm: HashAlgorithm,
+    /// File the hashes of allowed local configs are stored in, defaults to `config/hashes` in
+    /// `stdpath("data")`.
+    pub hashes_file: Option<String>,
+    /// Globs for the file names considered config files.
+    #[default(
+        _code = r#"vec!["*.yml".into(), "*.yaml".into(), "*.json".into(), "*.toml".into()]"#
+    )]
+    pub patterns: Vec<String>,
+    /// Path of the local config directories, searched for in the cwd and its ancestors.
+    #[default(_code = r#"".nvim/config".into()"#)]
+    pub local_dir: String,
+    /// Directories with local configs loaded in addition to the `local_dir` ones, subject to
+    /// the same trust rules.
+    pub extra_dirs: Vec<String>,
+    /// Load all configs again when a trusted config file is written.
+    pub auto_reload: bool,
+    /// Reapply the highlights of unconditional configs after `:colorscheme`.
+    #[default = true]
+    pub reapply_highlights: bool,
+    /// Skip entries already present when appending or prepending to list options.
+    pub dedupe_lists: bool,
+    /// Commalist options treated as maps of `key:value` entries in addition to [`MAP_OPTIONS`].
+    pub map_options: Vec<String>,
+    /// Warn about keymaps mapped to different rhs by multiple configs.
+    #[default = true]
+    pub warn_keymap_conflicts: bool,
+    /// Warn about options assigned different values by multiple configs.
+    pub strict_sets: bool,
+    /// Warn about options assigned different values under conditions that can match the same
+    /// buffer at once, the outcome of which depends on the order configs are applied in.
+    pub warn_condition_conflicts: bool,
+    /// Skip options unknown to the running Neovim silently instead of reporting them.
+    pub ignore_unknown_options: bool,
+    /// Undo global keymaps and non-buffer options of conditional configs when leaving a
+    /// matching buffer or the condition stops holding.
+    pub revert_conditional: bool,
+}
+
+thread_local! {
+    static SETUP: RefCell<Setup> = RefCell::default();
+}
+
+impl Setup {
+    pub fn get() -> Self {
+        SETUP.with(|setup| setup.borrow().clone())
+    }
+}
+
+pub fn setup(object: Object) -> Result<()> {
+    let setup = if object.kind() == ObjectKind::Nil {
+        Setup::default()
+    } else {
+        Setup::deserialize(oxi::serde::Deserializer::new(object))
+            .map_err(|error| ApiError::Other(format!("Invalid setup: {error}")))?
+    };
+    SETUP.with(|current| *current.borrow_mut() = setup);
+    Ok(())
+}
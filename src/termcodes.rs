@@ -0,0 +1,22 @@
+use crate::*;
+
+/// Translates key notation like `<Tab>`, `<C-x>` or `<Leader>` into the raw keys Neovim
+/// receives, for places that send keys instead of defining mappings.
+pub fn termcodes(keys: &str) -> String {
+    api::replace_termcodes(keys, true, true, true)
+        .to_string_lossy()
+        .into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[nvim_oxi::test]
+    fn key_notation_is_translated() {
+        assert_eq!(termcodes("<Tab>"), "\t");
+        assert_eq!(termcodes("<C-x>"), "\u{18}");
+        api::set_var("mapleader", ",").expect("leader is set");
+        assert_eq!(termcodes("<Leader>w"), ",w");
+    }
+}